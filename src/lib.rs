@@ -0,0 +1,1564 @@
+//! Reading environment variables from TOML, JSON and YAML files.
+//!
+//! This is the library behind the `envf` command; see [`load_files`] for the
+//! simplest entry point.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use base64::Engine;
+use flate2::read::GzDecoder;
+
+extern crate base64;
+extern crate flate2;
+extern crate glob;
+extern crate serde_json;
+extern crate serde_yaml;
+extern crate toml;
+#[cfg(feature = "url")]
+extern crate ureq;
+
+/// Variable names mapped to their values, in no particular order: sort the
+/// keys when the order matters.
+pub type EnvMap = HashMap<String, String>;
+
+pub type EnvMapOrError = Result<EnvMap, EnvfError>;
+
+/// What a file sets a variable to.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    /// A string, or a scalar written as one.
+    String(String),
+    /// The items of an array, joined with [`LoadOptions::array_separator`].
+    /// Only read if [`LoadOptions::mark_arrays`] is set.
+    Array(String),
+    /// `{ unset = true }`: the variable is removed from those of the files
+    /// before.
+    Unset,
+    /// A template of the `_compute` table, filled once all the files are
+    /// loaded with [`fill_template`].
+    Template(String),
+}
+
+impl Value {
+    /// The string the variable is set to, unless it is unset or computed.
+    pub fn into_string(self) -> Option<String> {
+        match self {
+            Value::String(s) | Value::Array(s) => Some(s),
+            Value::Unset | Value::Template(_) => None,
+        }
+    }
+}
+
+/// The variables of a file, before they are merged with those of others.
+pub type FileMap = HashMap<String, Value>;
+
+pub type FileMapOrError = Result<FileMap, EnvfError>;
+
+/// Settings controlling how the contents of a file are turned into variables.
+pub struct LoadOptions {
+    /// Joins the keys of nested tables: `[a] b = 1` becomes `a_b` by default.
+    pub separator: String,
+    /// Joins the items of arrays of scalars.
+    pub array_separator: String,
+    /// Only read the variables of this sub-table, as if they were top-level.
+    pub section: Option<String>,
+    /// Number of decimals floats are written with; as many as needed if unset.
+    pub float_precision: Option<usize>,
+    /// How booleans are written.
+    pub bool_style: BoolStyle,
+    /// The base integers are written in.
+    pub int_radix: IntRadix,
+    /// Prefix integers written in another base than 10 with `0x`, `0o` or `0b`.
+    pub radix_prefix: bool,
+    /// Read the strings made of decimal digits, without leading zeros, as
+    /// unsigned 64-bit integers, written as other integers are. Those too
+    /// large for 64 bits are kept as they are, with a warning.
+    pub int_u64: bool,
+    /// Ignore keys starting with `_`, at any depth, so they can hold comments.
+    pub skip_underscore: bool,
+    /// How datetimes are written.
+    pub datetime_format: DatetimeFormat,
+    /// Skip the values that can't be converted into a string, with a warning,
+    /// instead of rejecting the whole file.
+    pub skip_nonscalar: bool,
+    /// Read arrays as [`Value::Array`] rather than [`Value::String`], so that
+    /// they can be told from others when merging files.
+    pub mark_arrays: bool,
+    /// Split files at the lines holding only `---` and parse each part as a
+    /// document of its own, the later ones overriding the earlier ones. Parts
+    /// that can't be parsed are skipped with a warning.
+    pub multi_doc: bool,
+    /// Read the null values of JSON and YAML documents as `{ unset = true }`
+    /// instead of rejecting them.
+    pub null_as_unset: bool,
+    /// Write [`MASK`] instead of values in warnings.
+    pub mask: bool,
+    /// Only parse the documents, and those they include, without reading
+    /// variables from them: every file that can be parsed loads as empty.
+    pub parse_only: bool,
+    /// Called with a message when something is loaded but may not be what the
+    /// user expects. Does nothing by default.
+    pub warn: fn(&str),
+}
+
+/// The strings datetimes are written as.
+#[derive(Clone, Copy, Debug)]
+pub enum DatetimeFormat {
+    /// As in the file, following RFC 3339.
+    Rfc3339,
+    /// The number of seconds since 1970-01-01T00:00:00Z. Datetimes without an
+    /// offset are taken to be in UTC.
+    Epoch,
+}
+
+/// The bases integers can be written in. Negative integers are written as a
+/// `-` followed by their absolute value, as in `-0x1f`.
+#[derive(Clone, Copy, Debug)]
+pub enum IntRadix {
+    /// Base 10.
+    Dec,
+    /// Base 16, with lowercase digits.
+    Hex,
+    /// Base 8.
+    Oct,
+    /// Base 2.
+    Bin,
+}
+
+impl IntRadix {
+    fn render(self, x: i64, prefix: bool) -> String {
+        let sign = if x < 0 { "-" } else { "" };
+        format!("{}{}", sign, self.render_unsigned(x.unsigned_abs(), prefix))
+    }
+
+    fn render_unsigned(self, n: u64, prefix: bool) -> String {
+        let (marker, digits) = match self {
+            IntRadix::Dec => return format!("{}", n),
+            IntRadix::Hex => ("0x", format!("{:x}", n)),
+            IntRadix::Oct => ("0o", format!("{:o}", n)),
+            IntRadix::Bin => ("0b", format!("{:b}", n)),
+        };
+        if prefix {
+            format!("{}{}", marker, digits)
+        } else {
+            digits
+        }
+    }
+}
+
+/// The strings booleans are written as.
+#[derive(Clone, Copy, Debug)]
+pub enum BoolStyle {
+    /// `true` and `false`.
+    TrueFalse,
+    /// `1` and `0`.
+    OneZero,
+    /// `yes` and `no`.
+    YesNo,
+}
+
+impl BoolStyle {
+    fn render(self, b: bool) -> &'static str {
+        match (self, b) {
+            (BoolStyle::TrueFalse, true) => "true",
+            (BoolStyle::TrueFalse, false) => "false",
+            (BoolStyle::OneZero, true) => "1",
+            (BoolStyle::OneZero, false) => "0",
+            (BoolStyle::YesNo, true) => "yes",
+            (BoolStyle::YesNo, false) => "no",
+        }
+    }
+}
+
+/// Shows every setting but `warn`.
+impl fmt::Debug for LoadOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LoadOptions")
+            .field("separator", &self.separator)
+            .field("array_separator", &self.array_separator)
+            .field("section", &self.section)
+            .field("float_precision", &self.float_precision)
+            .field("bool_style", &self.bool_style)
+            .field("int_radix", &self.int_radix)
+            .field("radix_prefix", &self.radix_prefix)
+            .field("int_u64", &self.int_u64)
+            .field("skip_underscore", &self.skip_underscore)
+            .field("datetime_format", &self.datetime_format)
+            .field("skip_nonscalar", &self.skip_nonscalar)
+            .field("mark_arrays", &self.mark_arrays)
+            .field("multi_doc", &self.multi_doc)
+            .field("null_as_unset", &self.null_as_unset)
+            .field("mask", &self.mask)
+            .field("parse_only", &self.parse_only)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for LoadOptions {
+    fn default() -> Self {
+        LoadOptions {
+            separator: String::from("_"),
+            array_separator: String::from(":"),
+            section: None,
+            float_precision: None,
+            bool_style: BoolStyle::TrueFalse,
+            int_radix: IntRadix::Dec,
+            radix_prefix: false,
+            int_u64: false,
+            skip_underscore: true,
+            datetime_format: DatetimeFormat::Rfc3339,
+            skip_nonscalar: false,
+            mark_arrays: false,
+            multi_doc: false,
+            null_as_unset: false,
+            mask: false,
+            parse_only: false,
+            warn: |_| {},
+        }
+    }
+}
+
+/// Everything that can go wrong while loading variables.
+#[derive(Debug)]
+pub enum EnvfError {
+    /// A file couldn't be read.
+    Read(io::Error),
+    /// A gzip-compressed file couldn't be decompressed.
+    Gzip(io::Error),
+    /// A file isn't valid UTF-8.
+    Encoding(std::string::FromUtf8Error),
+    /// A file isn't valid TOML.
+    Parse(toml::de::Error),
+    /// A file isn't valid JSON.
+    Json(serde_json::Error),
+    /// A file isn't valid YAML.
+    Yaml(serde_yaml::Error),
+    /// A document isn't a table; the reason is given.
+    NotATable(String),
+    /// A YAML key isn't a string, number or boolean.
+    NonScalarKey { key: String },
+    /// A YAML value is null.
+    Null { key: String },
+    /// A value can't be converted into a string.
+    NonScalar { key: String, value: toml::Value },
+    /// The value of a `_B64` key isn't a string.
+    NotBase64String { key: String, value: toml::Value },
+    /// The value of a `_B64` key isn't base64-encoded UTF-8.
+    InvalidBase64 { key: String, reason: String },
+    /// A document couldn't be fetched; the reason is given.
+    Fetch(String),
+    /// A directory couldn't be listed.
+    ListDir(io::Error),
+    /// A document has no sub-table with the requested name.
+    MissingSection(String),
+    /// An environment variable expected to hold a document is unset or empty.
+    EmptyVariable,
+    /// A glob pattern is invalid.
+    Pattern(glob::PatternError),
+    /// A glob pattern couldn't be expanded.
+    Glob(glob::GlobError),
+    /// A glob pattern matched no file.
+    NoMatch,
+    /// The value of `key` references the undefined variable `name`.
+    UndefinedVariable { key: String, name: String },
+    /// Variables reference each other in a cycle.
+    CircularReference(Vec<String>),
+    /// The `_include` key isn't an array of strings.
+    InvalidInclude(toml::Value),
+    /// Files include each other in a cycle.
+    CircularInclude(Vec<String>),
+    /// The `_compute` key isn't a table of strings.
+    InvalidCompute(toml::Value),
+    /// `path` references the variable `name`, which isn't set.
+    UnsetPathVariable { path: String, name: String },
+    /// `path` has a `${` without a matching `}`.
+    UnclosedPathVariable(String),
+    /// The file at `path` couldn't be loaded.
+    File { path: String, error: Box<EnvfError> },
+}
+
+/// What values are replaced with in masked messages.
+pub const MASK: &str = "***";
+
+fn show_value(value: &toml::Value, mask: bool) -> String {
+    if mask {
+        String::from(MASK)
+    } else {
+        format!("{:?}", value)
+    }
+}
+
+impl EnvfError {
+    /// Displays the error with [`MASK`] in place of the values it mentions.
+    pub fn masked(&self) -> MaskedError<'_> {
+        MaskedError(self)
+    }
+
+    /// Whether the file, directory or URL couldn't be read at all, which may
+    /// not happen again, as opposed to holding something invalid.
+    pub fn is_read_failure(&self) -> bool {
+        matches!(self, EnvfError::Read(_) | EnvfError::ListDir(_) | EnvfError::Fetch(_))
+    }
+
+    /// Whether the file or directory to read doesn't exist, as opposed to
+    /// being unreadable or holding something invalid.
+    pub fn is_not_found(&self) -> bool {
+        match self {
+            EnvfError::Read(err) | EnvfError::ListDir(err) => {
+                err.kind() == io::ErrorKind::NotFound
+            }
+            _ => false,
+        }
+    }
+
+    fn describe(&self, f: &mut fmt::Formatter, mask: bool) -> fmt::Result {
+        match self {
+            EnvfError::Read(err) => write!(f, "Could not read contents: {}", err),
+            EnvfError::Parse(err) => match err.line_col() {
+                None => write!(f, "invalid TOML: {}", err),
+                Some((line, col)) => {
+                    let msg = err.to_string();
+                    let location = format!(" at line {} column {}", line + 1, col + 1);
+                    write!(
+                        f,
+                        "invalid TOML at line {}, column {}: {}",
+                        line + 1,
+                        col + 1,
+                        msg.strip_suffix(&location).unwrap_or(&msg)
+                    )
+                }
+            },
+            EnvfError::Gzip(err) => write!(f, "Could not decompress contents: {}", err),
+            EnvfError::Encoding(err) => write!(f, "Invalid UTF-8: {}", err),
+            EnvfError::Json(err) => write!(f, "Invalid JSON: {}", err),
+            EnvfError::Yaml(err) => write!(f, "Invalid YAML: {}", err),
+            EnvfError::NotATable(reason) => write!(f, "Unexpected format: {}", reason),
+            EnvfError::NonScalarKey { key } => {
+                write!(f, "key {} can't be converted into a string", key)
+            }
+            EnvfError::Null { key } => write!(f, "value for {} is null", key),
+            EnvfError::NonScalar { key, value } => write!(
+                f,
+                "value for {} ({}) can't be converted into a string",
+                key,
+                show_value(value, mask)
+            ),
+            EnvfError::NotBase64String { key, value } => write!(
+                f,
+                "value for {} ({}) must be a base64-encoded string",
+                key,
+                show_value(value, mask)
+            ),
+            EnvfError::InvalidBase64 { key, reason } => {
+                write!(f, "value for {} is not valid base64: {}", key, reason)
+            }
+            EnvfError::MissingSection(name) => write!(f, "No [{}] section", name),
+            EnvfError::EmptyVariable => write!(f, "Variable is unset or empty"),
+            EnvfError::Fetch(reason) => write!(f, "Could not fetch: {}", reason),
+            EnvfError::ListDir(err) => write!(f, "Could not list contents: {}", err),
+            EnvfError::Pattern(err) => write!(f, "Invalid pattern: {}", err),
+            EnvfError::Glob(err) => write!(f, "Could not expand pattern: {}", err),
+            EnvfError::NoMatch => write!(f, "No file matches this pattern"),
+            EnvfError::UndefinedVariable { key, name } => {
+                write!(f, "{} references undefined variable {}", key, name)
+            }
+            EnvfError::CircularReference(keys) => {
+                write!(f, "Circular reference: {}", keys.join(" -> "))
+            }
+            EnvfError::InvalidInclude(value) => {
+                write!(
+                    f,
+                    "_include ({}) must be an array of paths",
+                    show_value(value, mask)
+                )
+            }
+            EnvfError::InvalidCompute(value) => {
+                write!(
+                    f,
+                    "_compute ({}) must be a table of strings",
+                    show_value(value, mask)
+                )
+            }
+            EnvfError::CircularInclude(paths) => {
+                write!(f, "Circular include: {}", paths.join(" -> "))
+            }
+            EnvfError::UnsetPathVariable { path, name } => {
+                write!(f, "Can't expand {}: {} isn't set", path, name)
+            }
+            EnvfError::UnclosedPathVariable(path) => {
+                write!(f, "Can't expand {}: missing }} after ${{", path)
+            }
+            EnvfError::File { path, error } => {
+                write!(f, "{}: ", path)?;
+                error.describe(f, mask)
+            }
+        }
+    }
+}
+
+impl fmt::Display for EnvfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.describe(f, false)
+    }
+}
+
+/// An error displayed with its values masked; see [`EnvfError::masked`].
+pub struct MaskedError<'a>(&'a EnvfError);
+
+impl fmt::Display for MaskedError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.describe(f, true)
+    }
+}
+
+impl std::error::Error for EnvfError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EnvfError::Read(err) | EnvfError::ListDir(err) | EnvfError::Gzip(err) => Some(err),
+            EnvfError::Parse(err) => Some(err),
+            EnvfError::Encoding(err) => Some(err),
+            EnvfError::Json(err) => Some(err),
+            EnvfError::Yaml(err) => Some(err),
+            EnvfError::Pattern(err) => Some(err),
+            EnvfError::Glob(err) => Some(err),
+            EnvfError::File { error, .. } => Some(error.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// Reads each of `paths` in order and merges their variables with a
+/// [`Merge`], values from later files overriding those from earlier ones,
+/// then fills the `_compute` templates with the merged variables. Stops at
+/// the first file that can't be loaded.
+pub fn load_files(paths: &[String], options: &LoadOptions) -> EnvMapOrError {
+    let mut merge = Merge::new(options);
+    for path in paths {
+        let m = read_env_file(path, options).map_err(|error| EnvfError::File {
+            path: path.clone(),
+            error: Box::new(error),
+        })?;
+        merge.add_file(path, m);
+    }
+    merge.compute(false, false)?;
+    Ok(merge.map)
+}
+
+/// The variables of several files merged in order, along with where each was
+/// last set.
+pub struct Merge {
+    /// The variables set so far.
+    pub map: EnvMap,
+    /// Where each variable of `map` was last set.
+    pub origins: HashMap<String, String>,
+    /// Where the values each variable replaced came from, in order.
+    pub overridden: HashMap<String, Vec<String>>,
+    /// Keep the first value set for each variable rather than the last.
+    pub no_override: bool,
+    array_separator: String,
+    /// The variables last set from an array, which later arrays extend.
+    array_keys: HashSet<String>,
+    /// The `_compute` templates, with where each comes from.
+    computed: BTreeMap<String, (String, String)>,
+}
+
+/// What [`Merge::add`] did with a variable.
+#[derive(Debug, PartialEq)]
+pub enum Change {
+    /// The variable was set, replacing the value set by `previous` if any, or
+    /// extending it if `extended`.
+    Set {
+        previous: Option<String>,
+        extended: bool,
+    },
+    /// The variable was removed; `was_set` tells whether it had a value.
+    Unset { was_set: bool },
+    /// The variable will be set by [`Merge::compute`].
+    Computed,
+    /// The variable was left alone because of [`Merge::no_override`]. Holds
+    /// where the value kept was set.
+    Kept(String),
+}
+
+impl Merge {
+    /// An empty merge, extending values with the array separator of
+    /// `options`.
+    pub fn new(options: &LoadOptions) -> Self {
+        Merge {
+            map: EnvMap::new(),
+            origins: HashMap::new(),
+            overridden: HashMap::new(),
+            no_override: false,
+            array_separator: options.array_separator.clone(),
+            array_keys: HashSet::new(),
+            computed: BTreeMap::new(),
+        }
+    }
+
+    /// Adds the variables of the file at `path`, in key order. A key ending in
+    /// `+` appends its value to that of the key without it, if set. Returns
+    /// what was done with each variable.
+    pub fn add_file(&mut self, path: &str, m: FileMap) -> Vec<(String, Change)> {
+        self.add_renamed_file(path, m, |k| String::from(k))
+    }
+
+    /// Like [`Merge::add_file`], but renames the keys, once their `+` is
+    /// removed, with `rename`, and the placeholders of the `_compute`
+    /// templates too, so that they still stand for the same variables.
+    pub fn add_renamed_file(
+        &mut self,
+        path: &str,
+        m: FileMap,
+        rename: impl Fn(&str) -> String,
+    ) -> Vec<(String, Change)> {
+        let mut entries: Vec<(String, Value)> = m.into_iter().collect();
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        entries
+            .into_iter()
+            .map(|(k, v)| {
+                let (k, append) = match k.strip_suffix('+') {
+                    Some(base) => (rename(base), true),
+                    None => (rename(&k), false),
+                };
+                let v = match v {
+                    Value::Template(template) => {
+                        Value::Template(rename_placeholders(&template, &rename))
+                    }
+                    v => v,
+                };
+                let change = self.add(k.clone(), v, append, path);
+                (k, change)
+            })
+            .collect()
+    }
+
+    /// Adds `key`, set to `value` by `origin`. If `append`, or if both `value`
+    /// and the current value are arrays, `value` is appended to the current
+    /// value after the array separator.
+    pub fn add(&mut self, key: String, value: Value, append: bool, origin: &str) -> Change {
+        if self.no_override {
+            if let Some(kept) = self.origins.get(&key) {
+                return Change::Kept(kept.clone());
+            }
+        }
+        let (mut v, from_array) = match value {
+            Value::String(s) => (s, false),
+            Value::Array(items) => (items, true),
+            Value::Unset => {
+                let was_set = self.map.contains_key(&key);
+                self.unset(&key);
+                return Change::Unset { was_set };
+            }
+            Value::Template(template) => {
+                self.computed.insert(key, (template, String::from(origin)));
+                return Change::Computed;
+            }
+        };
+        let extended = append || (from_array && self.array_keys.contains(&key));
+        if extended {
+            match self.map.get(&key) {
+                Some(current) if v.is_empty() => v = current.clone(),
+                Some(current) if !current.is_empty() => {
+                    v = format!("{}{}{}", current, self.array_separator, v)
+                }
+                _ => {}
+            }
+        }
+        if from_array {
+            self.array_keys.insert(key.clone());
+        } else {
+            self.array_keys.remove(&key);
+        }
+        let previous = self.set(key, v, origin);
+        Change::Set { previous, extended }
+    }
+
+    /// Sets `key` to `value`, recording `origin` as where it comes from, and
+    /// returns where the value it replaces came from.
+    pub fn set(&mut self, key: String, value: String, origin: &str) -> Option<String> {
+        let previous = self.origins.insert(key.clone(), String::from(origin));
+        if let Some(previous) = &previous {
+            self.overridden.entry(key.clone()).or_default().push(previous.clone());
+        }
+        self.computed.remove(&key);
+        self.map.insert(key, value);
+        previous
+    }
+
+    /// Removes `key`, along with its template, and returns where it was set.
+    pub fn unset(&mut self, key: &str) -> Option<String> {
+        self.map.remove(key);
+        self.overridden.remove(key);
+        self.array_keys.remove(key);
+        let template = self.computed.remove(key).map(|(_, origin)| origin);
+        self.origins.remove(key).or(template)
+    }
+
+    /// Whether `key` is set, or will be by [`Merge::compute`].
+    pub fn is_set(&self, key: &str) -> bool {
+        self.map.contains_key(key) || self.computed.contains_key(key)
+    }
+
+    /// The variables set, or to be set by [`Merge::compute`], in no
+    /// particular order.
+    pub fn names(&self) -> Vec<String> {
+        self.map.keys().chain(self.computed.keys()).cloned().collect()
+    }
+
+    /// Fills the `_compute` templates with the variables set so far, as
+    /// [`fill_template`] does, and sets theirs. Returns their names in order,
+    /// each with where the value it replaced came from. If a template can't
+    /// be filled, fails with an [`EnvfError::File`] naming where it comes from.
+    pub fn compute(
+        &mut self,
+        allow_unset: bool,
+        inherit: bool,
+    ) -> Result<Vec<(String, Option<String>)>, EnvfError> {
+        let mut computed = vec![];
+        for (k, (template, origin)) in &self.computed {
+            match fill_template(k, template, &self.map, allow_unset, inherit) {
+                Err(error) => {
+                    return Err(EnvfError::File {
+                        path: origin.clone(),
+                        error: Box::new(error),
+                    })
+                }
+                Ok(v) => computed.push((k.clone(), v, origin.clone())),
+            }
+        }
+        Ok(computed
+            .into_iter()
+            .map(|(k, v, origin)| {
+                let previous = self.set(k.clone(), v, &origin);
+                (k, previous)
+            })
+            .collect())
+    }
+}
+
+/// Expands the `${NAME}` references in the values of `map`, looking NAME up in
+/// `map` itself (recursively) and then, if `inherit` is set, in the current
+/// environment.
+pub fn interpolate(map: &EnvMap, allow_unset: bool, inherit: bool) -> EnvMapOrError {
+    let mut done = EnvMap::new();
+    let mut keys: Vec<&String> = map.keys().collect();
+    keys.sort();
+    for key in keys {
+        resolve(key, map, allow_unset, inherit, &mut done, &mut vec![])?;
+    }
+    Ok(done)
+}
+
+fn resolve(
+    key: &str,
+    map: &EnvMap,
+    allow_unset: bool,
+    inherit: bool,
+    done: &mut EnvMap,
+    stack: &mut Vec<String>,
+) -> Result<String, EnvfError> {
+    if let Some(value) = done.get(key) {
+        return Ok(value.clone());
+    }
+    if stack.iter().any(|k| k == key) {
+        let mut keys = stack.clone();
+        keys.push(String::from(key));
+        return Err(EnvfError::CircularReference(keys));
+    }
+    stack.push(String::from(key));
+    let raw = &map[key];
+    let mut value = String::new();
+    let mut rest = raw.as_str();
+    while let Some(start) = rest.find('$') {
+        value.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if rest.starts_with("$${") {
+            value.push_str("${");
+            rest = &rest[3..];
+        } else if let (true, Some(end)) = (rest.starts_with("${"), rest.find('}')) {
+            let name = &rest[2..end];
+            rest = &rest[end + 1..];
+            if map.contains_key(name) {
+                value.push_str(&resolve(name, map, allow_unset, inherit, done, stack)?);
+            } else if let (true, Ok(inherited)) = (inherit, env::var(name)) {
+                value.push_str(&inherited);
+            } else if !allow_unset {
+                return Err(EnvfError::UndefinedVariable {
+                    key: String::from(key),
+                    name: String::from(name),
+                });
+            }
+        } else {
+            value.push('$');
+            rest = &rest[1..];
+        }
+    }
+    value.push_str(rest);
+    stack.pop();
+    done.insert(String::from(key), value.clone());
+    Ok(value)
+}
+
+/// Replaces the `$NAME` and `${NAME}` references in `path` with the value of
+/// the environment variable NAME, which must be set (and can't be if
+/// `inherit` isn't). `\$` stands for a literal `$`, as does a `$` that isn't
+/// followed by a name.
+pub fn expand_path(path: &str, inherit: bool) -> Result<String, EnvfError> {
+    let mut expanded = String::new();
+    let mut rest = path;
+    while let Some(i) = rest.find(['\\', '$']) {
+        let (before, from) = rest.split_at(i);
+        expanded.push_str(before);
+        if let Some(after) = from.strip_prefix('\\') {
+            match after.strip_prefix('$') {
+                Some(next) => {
+                    expanded.push('$');
+                    rest = next;
+                }
+                None => {
+                    expanded.push('\\');
+                    rest = after;
+                }
+            }
+            continue;
+        }
+        let after = &from[1..];
+        let (name, next) = match after.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], &braced[end + 1..]),
+                None => return Err(EnvfError::UnclosedPathVariable(String::from(path))),
+            },
+            None => {
+                let end = after
+                    .find(|c: char| c != '_' && !c.is_ascii_alphanumeric())
+                    .unwrap_or(after.len());
+                (&after[..end], &after[end..])
+            }
+        };
+        if !is_shell_identifier(name) {
+            expanded.push('$');
+            rest = after;
+            continue;
+        }
+        match env::var_os(name).filter(|_| inherit) {
+            Some(value) => expanded.push_str(&value.to_string_lossy()),
+            None => {
+                return Err(EnvfError::UnsetPathVariable {
+                    path: String::from(path),
+                    name: String::from(name),
+                })
+            }
+        }
+        rest = next;
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Whether `s` can be used as a variable name in a POSIX shell.
+pub fn is_shell_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c == '_' || c.is_ascii_alphabetic() => {
+            chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+        }
+        _ => false,
+    }
+}
+
+/// The path standing for the standard input.
+pub const STDIN_PATH: &str = "-";
+
+/// Reads the variables of the file at `path`, which is parsed as JSON or YAML
+/// if it has the corresponding extension, and as TOML otherwise.
+pub fn read_env_file(path: &str, options: &LoadOptions) -> FileMapOrError {
+    read_env_file_as(path, Format::of(path), options)
+}
+
+/// Like [`read_env_file`], but parses the file as `format` whatever its
+/// extension. The files it includes are still parsed according to theirs.
+pub fn read_env_file_as(path: &str, format: Format, options: &LoadOptions) -> FileMapOrError {
+    read_included_file(path, format, options, &mut Vec::new())
+}
+
+/// Turns `body`, the contents of the file at `path`, into variables. `path`
+/// is used to pick the format and to resolve `_include` paths.
+pub fn parse_env(path: &str, body: &str, options: &LoadOptions) -> FileMapOrError {
+    parse_included_env(path, Format::of(path), body, options, &mut Vec::new())
+}
+
+/// The languages the documents holding variables can be written in.
+#[derive(Clone, Copy, Debug)]
+pub enum Format {
+    /// A TOML table.
+    Toml,
+    /// A JSON object.
+    Json,
+    /// A YAML mapping.
+    Yaml,
+}
+
+impl Format {
+    /// Guesses the format of the file at `path` from its extension: JSON for
+    /// `.json`, YAML for `.yaml` and `.yml`, and TOML otherwise. A trailing
+    /// `.gz` is ignored.
+    pub fn of(path: &str) -> Format {
+        let path = path.strip_suffix(".gz").unwrap_or(path);
+        if path.ends_with(".json") {
+            Format::Json
+        } else if path.ends_with(".yaml") || path.ends_with(".yml") {
+            Format::Yaml
+        } else {
+            Format::Toml
+        }
+    }
+}
+
+/// The name of the key listing files to load before the document's own keys.
+const INCLUDE_KEY: &str = "_include";
+
+/// The name of the table of variables computed from the others once all the
+/// files are loaded, whose values are templates such as `"{HOST}:{PORT}"`.
+const COMPUTE_KEY: &str = "_compute";
+
+/// Reads the file at `path`, `including` being the chain of files whose
+/// `_include` led to it.
+fn read_included_file(
+    path: &str,
+    format: Format,
+    options: &LoadOptions,
+    including: &mut Vec<PathBuf>,
+) -> FileMapOrError {
+    let id = fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+    if including.contains(&id) {
+        let mut chain: Vec<String> = including
+            .iter()
+            .map(|p| p.display().to_string())
+            .collect();
+        chain.push(id.display().to_string());
+        return Err(EnvfError::CircularInclude(chain));
+    }
+    match read_contents(path) {
+        Err(err) => Err(err),
+        Ok(body) => {
+            including.push(id);
+            let result = parse_included_env(path, format, &body, options, including);
+            including.pop();
+            result
+        }
+    }
+}
+
+fn parse_included_env(
+    path: &str,
+    format: Format,
+    body: &str,
+    options: &LoadOptions,
+    including: &mut Vec<PathBuf>,
+) -> FileMapOrError {
+    let documents = if options.multi_doc { split_documents(body) } else { vec![body] };
+    if documents.len() < 2 {
+        return parse_document_env(path, format, body, options, including);
+    }
+    let mut map = FileMap::new();
+    for (i, document) in documents.into_iter().enumerate() {
+        if document.trim().is_empty() {
+            continue;
+        }
+        match parse_document_env(path, format, document, options, including) {
+            Ok(vars) => map.extend(vars),
+            Err(err @ EnvfError::CircularInclude(_)) => return Err(err),
+            Err(err) => (options.warn)(&format!(
+                "{}: document {} ignored: {}",
+                path,
+                i + 1,
+                if options.mask { err.masked().to_string() } else { err.to_string() }
+            )),
+        }
+    }
+    Ok(map)
+}
+
+/// Splits `body` at the lines holding only `---`, which are left out.
+fn split_documents(body: &str) -> Vec<&str> {
+    let mut documents = vec![];
+    let mut start = 0;
+    let mut offset = 0;
+    for line in body.split_inclusive('\n') {
+        if line.trim_end() == "---" {
+            documents.push(&body[start..offset]);
+            start = offset + line.len();
+        }
+        offset += line.len();
+    }
+    documents.push(&body[start..]);
+    documents
+}
+
+/// Turns `body`, a single document, into variables, after those of the files
+/// it includes.
+fn parse_document_env(
+    path: &str,
+    format: Format,
+    body: &str,
+    options: &LoadOptions,
+    including: &mut Vec<PathBuf>,
+) -> FileMapOrError {
+    let mut table = match parse_document(format, body, options)? {
+        toml::Value::Table(table) => table,
+        doc => {
+            return Err(EnvfError::NotATable(format!(
+                "expected a table, found {}",
+                doc.type_str()
+            )))
+        }
+    };
+    let mut map = FileMap::new();
+    if let Some(includes) = table.remove(INCLUDE_KEY) {
+        let dir = match Path::new(path).parent() {
+            Some(dir) if path != STDIN_PATH => dir,
+            _ => Path::new(""),
+        };
+        for include in include_paths(includes)? {
+            let included = dir.join(include).to_string_lossy().into_owned();
+            match read_included_file(&included, Format::of(&included), options, including) {
+                Ok(vars) => map.extend(vars),
+                Err(err @ EnvfError::CircularInclude(_)) => return Err(err),
+                Err(err) => {
+                    return Err(EnvfError::File {
+                        path: included,
+                        error: Box::new(err),
+                    })
+                }
+            }
+        }
+    }
+    if options.parse_only {
+        return Ok(map);
+    }
+    let scope = match &options.section {
+        None => &mut table,
+        Some(name) => match table.get_mut(name) {
+            Some(toml::Value::Table(section)) => section,
+            _ => return Err(EnvfError::MissingSection(name.clone())),
+        },
+    };
+    let compute = scope.remove(COMPUTE_KEY);
+    map.extend(table_into_env_map(scope, options)?);
+    if let Some(compute) = compute {
+        for (k, template) in compute_templates(compute)? {
+            map.insert(k, Value::Template(template));
+        }
+    }
+    Ok(map)
+}
+
+fn compute_templates(value: toml::Value) -> Result<Vec<(String, String)>, EnvfError> {
+    match value {
+        toml::Value::Table(table) if table.values().all(toml::Value::is_str) => Ok(table
+            .into_iter()
+            .filter_map(|(k, v)| v.as_str().map(|v| (k, String::from(v))))
+            .collect()),
+        value => Err(EnvfError::InvalidCompute(value)),
+    }
+}
+
+/// A part of a `_compute` template.
+enum TemplatePart<'a> {
+    /// Text standing for itself.
+    Text(&'a str),
+    /// `{{` or `}}`, standing for a single brace.
+    Brace(&'a str),
+    /// A `{KEY}` placeholder, holding KEY.
+    Placeholder(&'a str),
+}
+
+fn template_parts(template: &str) -> Vec<TemplatePart<'_>> {
+    let mut parts = vec![];
+    let mut rest = template;
+    while let Some(i) = rest.find(['{', '}']) {
+        let (before, from) = rest.split_at(i);
+        parts.push(TemplatePart::Text(before));
+        if from.starts_with("{{") || from.starts_with("}}") {
+            parts.push(TemplatePart::Brace(&from[..2]));
+            rest = &from[2..];
+            continue;
+        }
+        match from.strip_prefix('{').and_then(|after| after.find('}')) {
+            Some(end) => {
+                parts.push(TemplatePart::Placeholder(&from[1..end + 1]));
+                rest = &from[end + 2..];
+            }
+            None => {
+                parts.push(TemplatePart::Text(&from[..1]));
+                rest = &from[1..];
+            }
+        }
+    }
+    parts.push(TemplatePart::Text(rest));
+    parts
+}
+
+/// Replaces the `{KEY}` placeholders in `template` with the value of KEY in
+/// `map` or, if `inherit` is set, in the current environment, or with nothing
+/// if `allow_unset` and KEY isn't set. `{{` and `}}` stand for literal braces.
+pub fn fill_template(
+    key: &str,
+    template: &str,
+    map: &EnvMap,
+    allow_unset: bool,
+    inherit: bool,
+) -> Result<String, EnvfError> {
+    let mut filled = String::new();
+    for part in template_parts(template) {
+        match part {
+            TemplatePart::Text(text) => filled.push_str(text),
+            TemplatePart::Brace(brace) => filled.push_str(&brace[..1]),
+            TemplatePart::Placeholder(name) => match map.get(name) {
+                Some(value) => filled.push_str(value),
+                None => match (inherit, env::var(name)) {
+                    (true, Ok(inherited)) => filled.push_str(&inherited),
+                    _ if allow_unset => {}
+                    _ => {
+                        return Err(EnvfError::UndefinedVariable {
+                            key: String::from(key),
+                            name: String::from(name),
+                        })
+                    }
+                },
+            },
+        }
+    }
+    Ok(filled)
+}
+
+/// Renames the KEY of each `{KEY}` placeholder in `template` with `rename`.
+fn rename_placeholders(template: &str, rename: impl Fn(&str) -> String) -> String {
+    let mut renamed = String::new();
+    for part in template_parts(template) {
+        match part {
+            TemplatePart::Text(text) | TemplatePart::Brace(text) => renamed.push_str(text),
+            TemplatePart::Placeholder(name) => {
+                renamed.push('{');
+                renamed.push_str(&rename(name));
+                renamed.push('}');
+            }
+        }
+    }
+    renamed
+}
+
+fn include_paths(value: toml::Value) -> Result<Vec<String>, EnvfError> {
+    match value {
+        toml::Value::Array(items) if items.iter().all(toml::Value::is_str) => Ok(items
+            .into_iter()
+            .filter_map(|item| item.as_str().map(String::from))
+            .collect()),
+        value => Err(EnvfError::InvalidInclude(value)),
+    }
+}
+
+/// Fetches the document at `url` over HTTP(S) and turns it into variables as
+/// [`parse_env`] does. Always fails without the `url` feature.
+#[cfg(feature = "url")]
+pub fn read_env_url(url: &str, options: &LoadOptions) -> FileMapOrError {
+    let body = match ureq::get(url).call() {
+        Ok(response) => response.into_string().map_err(EnvfError::Read)?,
+        Err(ureq::Error::Status(code, response)) => {
+            return Err(EnvfError::Fetch(format!(
+                "HTTP status {} {}",
+                code,
+                response.status_text()
+            )))
+        }
+        Err(err) => return Err(EnvfError::Fetch(err.to_string())),
+    };
+    parse_env(url, &body, options)
+}
+
+#[cfg(not(feature = "url"))]
+pub fn read_env_url(_url: &str, _options: &LoadOptions) -> FileMapOrError {
+    Err(EnvfError::Fetch(String::from(
+        "envf was built without the url feature",
+    )))
+}
+
+/// Reads every `*.toml` file of `dir`, in lexical order, returning each path
+/// along with its result so failures can be reported individually.
+pub fn read_env_dir(
+    dir: &str,
+    options: &LoadOptions,
+) -> io::Result<Vec<(String, FileMapOrError)>> {
+    let mut paths: Vec<String> = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension().is_some_and(|ext| ext == "toml") {
+            paths.push(path.to_string_lossy().into_owned());
+        }
+    }
+    paths.sort();
+    Ok(read_env_files(paths, options))
+}
+
+/// Whether `path` should be expanded with [`expand_glob`].
+pub fn is_glob(path: &str) -> bool {
+    path.contains(['*', '?', '['])
+}
+
+/// Returns the paths matching `pattern`, in lexical order.
+pub fn expand_glob(pattern: &str) -> Result<Vec<String>, EnvfError> {
+    let entries = glob::glob(pattern).map_err(EnvfError::Pattern)?;
+    let mut paths: Vec<String> = vec![];
+    for entry in entries {
+        match entry {
+            Err(err) => return Err(EnvfError::Glob(err)),
+            Ok(path) => paths.push(path.to_string_lossy().into_owned()),
+        }
+    }
+    if paths.is_empty() {
+        return Err(EnvfError::NoMatch);
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Calls [`read_env_file`] on each of `paths`, pairing each with its result.
+pub fn read_env_files(paths: Vec<String>, options: &LoadOptions) -> Vec<(String, FileMapOrError)> {
+    paths
+        .into_iter()
+        .map(|path| {
+            let result = read_env_file(&path, options);
+            (path, result)
+        })
+        .collect()
+}
+
+/// Reads a classic `.env` file of `KEY=VALUE` lines, returning its variables
+/// along with the numbers of the lines that couldn't be understood.
+///
+/// Blank lines and lines starting with `#` are ignored, keys may be preceded
+/// by `export`, and values may be single-quoted (taken literally) or
+/// double-quoted (where `\n`, `\"` and `\\` are unescaped).
+pub fn read_dotenv_file(path: &str) -> Result<(EnvMap, Vec<usize>), EnvfError> {
+    let body = read_contents(path)?;
+    let mut map = EnvMap::new();
+    let mut malformed: Vec<usize> = vec![];
+    for (i, line) in body.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match parse_dotenv_line(line) {
+            None => malformed.push(i + 1),
+            Some((k, v)) => {
+                map.insert(k, v);
+            }
+        }
+    }
+    Ok((map, malformed))
+}
+
+fn parse_dotenv_line(line: &str) -> Option<(String, String)> {
+    let line = line.strip_prefix("export ").unwrap_or(line);
+    let (key, value) = line.split_at(line.find('=')?);
+    let key = key.trim();
+    if key.is_empty() || key.contains(char::is_whitespace) {
+        return None;
+    }
+    let value = value[1..].trim();
+    let (value, rest) = if let Some(quoted) = value.strip_prefix('\'') {
+        let end = quoted.find('\'')?;
+        (String::from(&quoted[..end]), &quoted[end + 1..])
+    } else if let Some(quoted) = value.strip_prefix('"') {
+        let mut unescaped = String::new();
+        let mut chars = quoted.char_indices();
+        let end = loop {
+            match chars.next()? {
+                (i, '"') => break i,
+                (_, '\\') => match chars.next()? {
+                    (_, 'n') => unescaped.push('\n'),
+                    (_, c) => unescaped.push(c),
+                },
+                (_, c) => unescaped.push(c),
+            }
+        };
+        (unescaped, &quoted[end + 1..])
+    } else {
+        let end = value.find(" #").unwrap_or(value.len());
+        (String::from(value[..end].trim_end()), "")
+    };
+    let rest = rest.trim_start();
+    if rest.is_empty() || rest.starts_with('#') {
+        Some((String::from(key), value))
+    } else {
+        None
+    }
+}
+
+/// The first bytes of gzip-compressed data.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Reads the file at `path`, decompressing it if it ends in `.gz` or looks
+/// gzip-compressed.
+fn read_contents(path: &str) -> Result<String, EnvfError> {
+    let bytes = if path == STDIN_PATH {
+        let mut bytes = vec![];
+        io::stdin().read_to_end(&mut bytes).map(|_| bytes)
+    } else {
+        fs::read(path)
+    }
+    .map_err(EnvfError::Read)?;
+    if path.ends_with(".gz") || bytes.starts_with(&GZIP_MAGIC) {
+        let mut body = String::new();
+        GzDecoder::new(&bytes[..])
+            .read_to_string(&mut body)
+            .map_err(EnvfError::Gzip)?;
+        Ok(body)
+    } else {
+        String::from_utf8(bytes).map_err(EnvfError::Encoding)
+    }
+}
+
+fn parse_document(
+    format: Format,
+    body: &str,
+    options: &LoadOptions,
+) -> Result<toml::Value, EnvfError> {
+    match format {
+        Format::Json if options.null_as_unset => serde_json::from_str(body)
+            .and_then(|doc| serde_json::from_value(json_nulls_into_unset(doc)))
+            .map_err(EnvfError::Json),
+        Format::Json => serde_json::from_str::<toml::Value>(body).map_err(EnvfError::Json),
+        Format::Yaml => match serde_yaml::from_str::<serde_yaml::Value>(body) {
+            Err(err) => Err(EnvfError::Yaml(err)),
+            Ok(doc) => yaml_into_toml(&doc, None, options.null_as_unset),
+        },
+        Format::Toml => body.parse::<toml::Value>().map_err(EnvfError::Parse),
+    }
+}
+
+/// Replaces the null values of the objects in `v` with `{ "unset": true }`.
+fn json_nulls_into_unset(v: serde_json::Value) -> serde_json::Value {
+    match v {
+        serde_json::Value::Object(object) => object
+            .into_iter()
+            .map(|(k, v)| {
+                if v.is_null() {
+                    let mut marker = serde_json::Map::new();
+                    marker.insert(String::from("unset"), serde_json::Value::Bool(true));
+                    (k, serde_json::Value::Object(marker))
+                } else {
+                    (k, json_nulls_into_unset(v))
+                }
+            })
+            .collect(),
+        serde_json::Value::Array(items) => items.into_iter().map(json_nulls_into_unset).collect(),
+        v => v,
+    }
+}
+
+/// Converts `v`, the value of `key` (`None` for the whole document), into the
+/// TOML value it stands for. The null values of mappings become
+/// `{ unset = true }` if `null_as_unset`, and are rejected otherwise.
+fn yaml_into_toml(
+    v: &serde_yaml::Value,
+    key: Option<&str>,
+    null_as_unset: bool,
+) -> Result<toml::Value, EnvfError> {
+    match v {
+        serde_yaml::Value::Null => match key {
+            None => Err(EnvfError::NotATable(String::from("null document"))),
+            Some(key) => Err(EnvfError::Null {
+                key: String::from(key),
+            }),
+        },
+        serde_yaml::Value::Bool(x) => Ok(toml::Value::Boolean(*x)),
+        serde_yaml::Value::Number(x) => match x.as_i64() {
+            Some(i) => Ok(toml::Value::Integer(i)),
+            None => Ok(toml::Value::Float(x.as_f64().unwrap_or(f64::NAN))),
+        },
+        serde_yaml::Value::String(s) => Ok(toml::Value::String(String::from(s))),
+        serde_yaml::Value::Sequence(seq) => seq
+            .iter()
+            .map(|item| yaml_into_toml(item, key, null_as_unset))
+            .collect::<Result<Vec<toml::Value>, EnvfError>>()
+            .map(toml::Value::Array),
+        serde_yaml::Value::Mapping(mapping) => {
+            let mut table = toml::value::Table::new();
+            for (k, v) in mapping {
+                let key = match k {
+                    serde_yaml::Value::String(s) => String::from(s),
+                    serde_yaml::Value::Number(x) => format!("{}", x),
+                    serde_yaml::Value::Bool(x) => format!("{}", x),
+                    _ => {
+                        return Err(EnvfError::NonScalarKey {
+                            key: format!("{:?}", k),
+                        })
+                    }
+                };
+                if v.is_null() && null_as_unset {
+                    let mut marker = toml::value::Table::new();
+                    marker.insert(String::from("unset"), toml::Value::Boolean(true));
+                    table.insert(key, toml::Value::Table(marker));
+                    continue;
+                }
+                let value = yaml_into_toml(v, Some(&key), null_as_unset)?;
+                table.insert(key, value);
+            }
+            Ok(toml::Value::Table(table))
+        }
+        serde_yaml::Value::Tagged(tagged) => yaml_into_toml(&tagged.value, key, null_as_unset),
+    }
+}
+
+/// Turns a parsed document into variables, flattening nested tables.
+pub fn table_into_env_map(table: &toml::value::Table, options: &LoadOptions) -> FileMapOrError {
+    let mut m = FileMap::new();
+    flatten_table(&mut m, "", table, options)?;
+    Ok(m)
+}
+
+fn flatten_table(
+    m: &mut FileMap,
+    prefix: &str,
+    table: &toml::value::Table,
+    options: &LoadOptions,
+) -> Result<(), EnvfError> {
+    for (k, v) in table {
+        if options.skip_underscore && k.starts_with('_') {
+            continue;
+        }
+        let key = if prefix.is_empty() {
+            String::from(k)
+        } else {
+            format!("{}{}{}", prefix, options.separator, k)
+        };
+        add_field(m, key, v, options)?;
+    }
+    Ok(())
+}
+
+const BASE64_SUFFIX: &str = "_B64";
+
+fn is_unset_marker(t: &toml::value::Table) -> bool {
+    t.len() == 1 && t.get("unset") == Some(&toml::Value::Boolean(true))
+}
+
+fn add_field(
+    m: &mut FileMap,
+    k: String,
+    v: &toml::Value,
+    options: &LoadOptions,
+) -> Result<(), EnvfError> {
+    match v {
+        toml::Value::String(encoded) if k.ends_with(BASE64_SUFFIX) => {
+            let s = base64::engine::general_purpose::STANDARD
+                .decode(encoded)
+                .map_err(|err| err.to_string())
+                .and_then(|bytes| String::from_utf8(bytes).map_err(|err| err.to_string()))
+                .map_err(|reason| EnvfError::InvalidBase64 {
+                    key: k.clone(),
+                    reason,
+                })?;
+            m.insert(String::from(&k[..k.len() - BASE64_SUFFIX.len()]), Value::String(s));
+        }
+        _ if k.ends_with(BASE64_SUFFIX) => {
+            return Err(EnvfError::NotBase64String {
+                key: k,
+                value: v.clone(),
+            })
+        }
+        toml::Value::Table(t) if is_unset_marker(t) => {
+            m.insert(k, Value::Unset);
+        }
+        toml::Value::Table(t) => flatten_table(m, &k, t, options)?,
+        // Arrays of tables: each table is flattened under its index.
+        toml::Value::Array(items)
+            if !items.is_empty() && items.iter().all(toml::Value::is_table) =>
+        {
+            for (i, item) in items.iter().enumerate() {
+                add_field(m, format!("{}{}{}", k, options.separator, i), item, options)?;
+            }
+        }
+        _ => match stringify(&k, v, options) {
+            Some(s) if options.mark_arrays && v.is_array() => {
+                m.insert(k, Value::Array(s));
+            }
+            Some(s) => {
+                m.insert(k, Value::String(s));
+            }
+            None if options.skip_nonscalar => (options.warn)(&format!(
+                "value for {} can't be converted into a string, skipping it",
+                k
+            )),
+            None => {
+                return Err(EnvfError::NonScalar {
+                    key: k,
+                    value: v.clone(),
+                })
+            }
+        },
+    }
+    Ok(())
+}
+
+fn stringify(k: &str, v: &toml::Value, options: &LoadOptions) -> Option<String> {
+    match v {
+        toml::value::Value::Array(items) => items
+            .iter()
+            .map(|item| stringify_scalar(k, item, options))
+            .collect::<Option<Vec<String>>>()
+            .map(|items| items.join(&options.array_separator)),
+        _ => stringify_scalar(k, v, options),
+    }
+}
+
+/// Reads `s` as shells do in arithmetic: in hexadecimal after 0x, in octal
+/// after any other leading 0, and in decimal otherwise. Returns `None` for
+/// the strings shells don't read as integers.
+fn shell_integer(s: &str) -> Option<i128> {
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, s),
+    };
+    let magnitude = if let Some(hex) = digits.strip_prefix("0x").or(digits.strip_prefix("0X")) {
+        u64::from_str_radix(hex, 16)
+    } else if digits.len() > 1 && digits.starts_with('0') {
+        u64::from_str_radix(&digits[1..], 8)
+    } else {
+        digits.parse()
+    }
+    .ok()?;
+    if negative {
+        Some(-i128::from(magnitude))
+    } else {
+        Some(i128::from(magnitude))
+    }
+}
+
+/// Warns if shells would read `rendered`, the string written for the integer
+/// `x` of `k`, as another integer.
+fn check_shell_integer(k: &str, rendered: &str, x: i128, options: &LoadOptions) {
+    match shell_integer(rendered) {
+        Some(read) if read != x => {
+            let show = |s: String| if options.mask { String::from(MASK) } else { s };
+            (options.warn)(&format!(
+                "value for {} ({}) would be read by shells as {} rather than {}",
+                k,
+                show(String::from(rendered)),
+                show(read.to_string()),
+                show(x.to_string())
+            ))
+        }
+        _ => {}
+    }
+}
+
+/// Reads `s` as an unsigned integer if it is made of decimal digits without
+/// leading zeros, warning when it doesn't fit in 64 bits.
+fn u64_string(k: &str, s: &str, options: &LoadOptions) -> Option<u64> {
+    let digits = !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    if !digits || (s.len() > 1 && s.starts_with('0')) {
+        return None;
+    }
+    let n = s.parse().ok();
+    if n.is_none() {
+        (options.warn)(&format!(
+            "value for {} ({}) doesn't fit in 64 bits, keeping it as is",
+            k,
+            if options.mask { MASK } else { s }
+        ));
+    }
+    n
+}
+
+fn stringify_scalar(k: &str, v: &toml::Value, options: &LoadOptions) -> Option<String> {
+    match v {
+        toml::value::Value::String(s) if options.int_u64 => match u64_string(k, s, options) {
+            Some(n) => {
+                let rendered = options.int_radix.render_unsigned(n, options.radix_prefix);
+                check_shell_integer(k, &rendered, i128::from(n), options);
+                Some(rendered)
+            }
+            None => Some(String::from(s)),
+        },
+        toml::value::Value::String(s) => Some(String::from(s)),
+        toml::value::Value::Integer(x) => {
+            let rendered = options.int_radix.render(*x, options.radix_prefix);
+            check_shell_integer(k, &rendered, i128::from(*x), options);
+            Some(rendered)
+        }
+        toml::value::Value::Float(x) => match options.float_precision {
+            None => Some(format!("{}", x)),
+            Some(precision) => Some(format!("{:.*}", precision, x)),
+        },
+        toml::value::Value::Boolean(x) => Some(String::from(options.bool_style.render(*x))),
+        toml::value::Value::Datetime(x) => match options.datetime_format {
+            DatetimeFormat::Rfc3339 => Some(format!("{}", x)),
+            DatetimeFormat::Epoch => {
+                let rfc3339 = x.to_string();
+                match datetime_to_epoch(&rfc3339) {
+                    None => {
+                        (options.warn)(&format!(
+                            "value for {} ({}) has no date, keeping it as is",
+                            k,
+                            if options.mask { MASK } else { &rfc3339 }
+                        ));
+                        Some(rfc3339)
+                    }
+                    Some((epoch, has_offset)) => {
+                        if !has_offset {
+                            (options.warn)(&format!(
+                                "value for {} ({}) has no offset, assuming UTC",
+                                k,
+                                if options.mask { MASK } else { &rfc3339 }
+                            ));
+                        }
+                        Some(format!("{}", epoch))
+                    }
+                }
+            }
+        },
+        _ => None,
+    }
+}
+
+/// Converts an RFC 3339 datetime, as written by `toml::value::Datetime`, into
+/// seconds since the Unix epoch (ignoring fractions of seconds), along with
+/// whether it had an offset. Returns `None` for times without a date.
+fn datetime_to_epoch(s: &str) -> Option<(i64, bool)> {
+    let number = |from: usize, to: usize| s.get(from..to)?.parse::<i64>().ok();
+    let (year, month, day) = (number(0, 4)?, number(5, 7)?, number(8, 10)?);
+    if s.get(4..5)? != "-" {
+        return None;
+    }
+    let (mut seconds, rest) = if s.len() > 10 {
+        let (h, m, sec) = (number(11, 13)?, number(14, 16)?, number(17, 19)?);
+        let rest = s[19..].trim_start_matches(|c: char| c == '.' || c.is_ascii_digit());
+        (h * 3600 + m * 60 + sec, rest)
+    } else {
+        (0, "")
+    };
+    let has_offset = !rest.is_empty();
+    if rest.starts_with('+') || rest.starts_with('-') {
+        let hours = rest.get(1..3)?.parse::<i64>().ok()?;
+        let minutes = rest.get(4..6)?.parse::<i64>().ok()?;
+        let offset = hours * 3600 + minutes * 60;
+        seconds -= if rest.starts_with('+') { offset } else { -offset };
+    }
+    // Days since 1970-01-01 in the proleptic Gregorian calendar, following
+    // http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    Some((days * 86400 + seconds, has_offset))
+}
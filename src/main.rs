@@ -1,178 +1,3142 @@
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::env;
-use std::error::Error;
 use std::fs;
+use std::io;
+use std::io::{IsTerminal, Write};
+use std::iter;
+use std::mem;
+use std::path::Path;
 use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
+#[cfg(unix)]
+use std::ffi::{CStr, CString, OsString};
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+#[cfg(unix)]
 use std::os::unix::process::CommandExt;
+#[cfg(unix)]
+use std::os::unix::process::ExitStatusExt;
+#[cfg(unix)]
+use std::ptr;
+#[cfg(unix)]
+use std::sync::atomic::AtomicI32;
 
+extern crate envf;
+extern crate serde_json;
 extern crate toml;
+#[cfg(unix)]
+extern crate libc;
 
-type EnvMap = HashMap<String, String>;
-
-type EnvMapOrError = Result<EnvMap, String>;
+use envf::{
+    expand_glob, expand_path, interpolate, is_glob, is_shell_identifier, parse_env,
+    read_dotenv_file, read_env_dir, read_env_file, read_env_file_as, read_env_url, BoolStyle,
+    Change, DatetimeFormat, EnvMap, EnvfError, FileMap, FileMapOrError, Format, IntRadix,
+    LoadOptions, Merge, Value, MASK, STDIN_PATH,
+};
 
 fn print_usage() {
-    eprintln!("Usage: envf [(-f FILE) ...] [-s] COMMAND ...");
-    eprintln!("");
+    eprintln!("Usage: envf [OPTION ...] [--] COMMAND ...");
+    eprintln!("       envf [OPTION ...] --chain [--] COMMAND ... [--- COMMAND ...]");
+    eprintln!("       envf [OPTION ...] (--print | --export)");
+    eprintln!("       envf [OPTION ...] (--validate | --parse-only)");
+    eprintln!();
     eprintln!("Run COMMAND in an environment augmented with the variables listed in each FILE.");
-    eprintln!("");
+    eprintln!("With --chain, several COMMANDs separated by --- are run in turn, each as a child");
+    eprintln!("process but the last, stopping at the first that fails, whose status envf then");
+    eprintln!("exits with. Without it, --- is passed to COMMAND like any other argument.");
+    eprintln!();
     eprintln!("Options:");
-    eprintln!("  -f FILE     Add values read from FILE to the environment in which COMMAND is run.");
-    eprintln!("              FILE is a TOML (https://github.com/toml-lang/toml) table of scalar values.");
-    eprintln!("              Default: .env.toml");
-    eprintln!("  -s          Silence warnings about unprocessable files.");
-    eprintln!("  -h, --help  Display this message.");
-    eprintln!("");
+    eprintln!("  -f FILE          Add values read from FILE to the environment in which COMMAND is run.");
+    eprintln!("                   FILE is a TOML (https://github.com/toml-lang/toml) table of scalar values.");
+    eprintln!("                   FILEs ending in .json are read as a JSON object instead,");
+    eprintln!("                   and FILEs ending in .yaml or .yml as a YAML mapping.");
+    eprintln!("                   Gzip-compressed FILEs are decompressed first; a trailing .gz is");
+    eprintln!("                   ignored when looking at the extension (as in app.json.gz).");
+    eprintln!("                   If FILE contains *, ? or [, it is a glob pattern and all the");
+    eprintln!("                   matching files are read, in lexical order.");
+    eprintln!("                   If FILE is -, values are read from the standard input (at most once).");
+    eprintln!("                   String values whose key ends in _B64 are base64-decoded and");
+    eprintln!("                   stored under the key without that suffix.");
+    eprintln!("                   ${{NAME}} in values is replaced with the value of the variable NAME,");
+    eprintln!("                   read from the files or else (without --clear) from the current");
+    eprintln!("                   environment; write $${{ for a literal ${{.");
+    eprintln!("                   Keys starting with _ are ignored, as are the tables they name.");
+    eprintln!("                   The value of a key ending in + (\"PATH+\") is appended to that");
+    eprintln!("                   of the key without it, if already set, after the array separator.");
+    eprintln!("                   A key whose value is the table {{ unset = true }} is removed,");
+    eprintln!("                   along with its value from the files before, instead of being set.");
+    eprintln!("                   Strings are always set as written, whatever they hold.");
+    eprintln!("                   The files listed in an _include array, relative to FILE's");
+    eprintln!("                   directory, are loaded first; FILE's own values override theirs.");
+    eprintln!("                   The strings of a _compute table are templates: {{NAME}} in them is");
+    eprintln!("                   replaced with the value of NAME once all the files are loaded,");
+    eprintln!("                   so that URL = \"{{HOST}}:{{PORT}}\" sets URL too. Write {{{{ for a {{.");
+    eprintln!("                   If FILE starts with !, it is required: failing to load it is an");
+    eprintln!("                   error even without --strict (use ./!FILE for a file named !FILE).");
+    eprintln!("                   $NAME and ${{NAME}} in FILE are replaced with the value of the");
+    eprintln!("                   variable NAME of the current environment, which must be set;");
+    eprintln!("                   write \\$ for a literal $. This also applies to --dotenv and -d.");
+    eprintln!("                   A leading ~ or ~USER in FILE stands for the home directory of the");
+    eprintln!("                   current user or of USER, unless --no-expand-tilde is given.");
+    eprintln!("                   Default: .env.toml");
+    eprintln!("  --env-file-format FORMAT");
+    eprintln!("                   Parse the FILEs of the following -f options as toml, json, yaml");
+    eprintln!("                   or dotenv (see --dotenv) whatever their extension, or according");
+    eprintln!("                   to it with auto, the default.");
+    eprintln!("  --env NAME       After each -f FILE that isn't a glob pattern, also read FILE with");
+    eprintln!("                   .NAME inserted before its extension (app.production.toml for");
+    eprintln!("                   app.toml with --env production), so that its values win. It's");
+    eprintln!("                   skipped if it doesn't exist, unless --strict is given. --layer");
+    eprintln!("                   files are read after it.");
+    eprintln!("  --layer NAME     After each -f FILE that isn't a glob pattern, also read FILE with");
+    eprintln!("                   .NAME inserted before its extension (config.local.toml for");
+    eprintln!("                   config.toml with --layer local, and config.local.json.gz for");
+    eprintln!("                   config.json.gz), if it exists.");
+    eprintln!("  --file-list FILE Add values read from each file listed in FILE, one per line, as");
+    eprintln!("                   with -f. Blank lines and lines starting with # are ignored, and");
+    eprintln!("                   relative paths are read from FILE's directory; - stands for the");
+    eprintln!("                   standard input. Failing to read FILE itself is an error.");
+    eprintln!("  --dotenv FILE    Add values read from FILE, a classic .env file of KEY=VALUE lines.");
+    eprintln!("                   Lines that can't be understood are ignored with a warning.");
+    eprintln!("  --from-env VAR   Add values read from the TOML document held by the variable VAR.");
+    eprintln!("  --url URL        Add values read from the document at URL, fetched over HTTP(S), as");
+    eprintln!("                   for -f. Only available if envf is built with the url feature.");
+    eprintln!("  -d DIR           Add values read from each *.toml file in DIR, in lexical order.");
+    eprintln!("  -e KEY=VALUE     Set KEY to VALUE, overriding the values read from the files.");
+    eprintln!("  --default KEY=VALUE");
+    eprintln!("                   Set KEY to VALUE unless it is set by a file, by -e, or (without");
+    eprintln!("                   --clear) by the current environment.");
+    eprintln!("  --no-override    Keep the first value the files give each variable, instead of the");
+    eprintln!("                   last: later files can't change, append to or unset it.");
+    eprintln!("                   Whatever the order of the files, variables are set by, from the");
+    eprintln!("                   highest precedence: -e; the files; the current environment,");
+    eprintln!("                   which only overrides the files with --defaults-only; --default.");
+    eprintln!("  --defaults-only  Treat all the values read from the files like --default ones:");
+    eprintln!("                   ignore those of the variables the current environment sets.");
+    eprintln!("  --rename OLD=NEW Rename the variable OLD to NEW once everything is loaded.");
+    eprintln!("  --map-file FILE  Rename variables as listed in FILE, a TOML table of OLD = \"NEW\"");
+    eprintln!("                   entries, before applying the --rename options.");
+    eprintln!("  --unset KEY      Remove KEY from the environment of COMMAND, even if it is inherited");
+    eprintln!("                   or read from a file.");
+    eprintln!("  --append-path KEY");
+    eprintln!("                   Append the value of KEY read from the files to that of the current");
+    eprintln!("                   environment (without --clear), after : (; on Windows), instead of");
+    eprintln!("                   replacing it. May be repeated.");
+    eprintln!("  --only PREFIX    Only pass the variables set by envf whose name starts with PREFIX.");
+    eprintln!("                   May be repeated to allow several prefixes.");
+    eprintln!("  --exclude KEY    Don't pass KEY even if it was read from a file. May be repeated.");
+    eprintln!("  --require KEY    Abort unless KEY is set, by envf or (without --clear) by the current");
+    eprintln!("                   environment. May be repeated.");
+    eprintln!("  --clear          Neither pass the current environment to COMMAND nor read it: COMMAND");
+    eprintln!("                   only sees the variables set by envf, and ${{NAME}}, --default,");
+    eprintln!("                   --require, --append-path and --defaults-only ignore the current");
+    eprintln!("                   environment. Applied before --unset.");
+    eprintln!("  --no-inherit     Neither pass nor read the current environment: only the files, -e");
+    eprintln!("                   and --default count. Implies --clear, and also applies to envf's own");
+    eprintln!("                   settings, such as the variables in the paths of files.");
+    eprintln!("  -s               Silence warnings about unprocessable files.");
+    eprintln!("  --strict         Abort instead of warning when a file can't be processed.");
+    eprintln!("                   Can't be combined with -s.");
+    eprintln!("  --retry N        Try reading each file, directory or URL up to N more times if it");
+    eprintln!("                   can't be read at all, as opposed to holding something invalid.");
+    eprintln!("                   Default: 0");
+    eprintln!("  --retry-delay MS Wait MS milliseconds before each --retry. Default: 500");
+    eprintln!("  --on-missing-file ACTION");
+    eprintln!("                   What to do about the files that don't exist: abort (error), skip");
+    eprintln!("                   them silently as if they weren't given (ignore), or handle them");
+    eprintln!("                   like the other files that can't be processed (warn, the");
+    eprintln!("                   default).");
+    eprintln!("  --keep-going     Instead of warning as each file fails to be processed, list all of");
+    eprintln!("                   them together once the files are loaded. Can't be combined with");
+    eprintln!("                   --strict.");
+    eprintln!("  --fail-on-skip   With --print and its variants, exit with status 1 after printing");
+    eprintln!("                   if any file couldn't be processed. COMMAND still runs regardless,");
+    eprintln!("                   unlike with --strict, and its own status is kept.");
+    eprintln!("  --require-nonempty");
+    eprintln!("                   Abort if no variable is set by the files, -e or --default, for");
+    eprintln!("                   instance because none of the files could be processed.");
+    eprintln!("  --allow-unset    Replace references to undefined variables with an empty string");
+    eprintln!("                   instead of aborting.");
+    eprintln!("  --print          Instead of running COMMAND, print the variables set by envf as");
+    eprintln!("                   KEY=VALUE lines sorted by KEY. COMMAND is then optional.");
+    eprintln!("  --format FORMAT  How --print renders the variables: plain (KEY=VALUE lines, the");
+    eprintln!("                   default) or json (an object of strings).");
+    eprintln!("  --export         Like --print, but as POSIX shell export statements, for use with");
+    eprintln!("                   eval. Variables whose name isn't a shell identifier are skipped.");
+    eprintln!("                   All --print formats sort variables by name, for stable output.");
+    eprintln!("  --diff FILE      Instead of running COMMAND, compare the KEY=VALUE lines --print would");
+    eprintln!("                   write with those of FILE, print those that differ (- for FILE's,");
+    eprintln!("                   + for envf's), and exit with status 1 if any does. COMMAND is");
+    eprintln!("                   then optional.");
+    eprintln!("  -o FILE          Have --print and its variants write to FILE instead of stdout.");
+    eprintln!("                   FILE is only replaced once everything is written.");
+    eprintln!("  --quote-style STYLE");
+    eprintln!("                   How --export quotes values: in single quotes (single, the");
+    eprintln!("                   default), in double quotes so that $NAME is expanded (double),");
+    eprintln!("                   or not at all (none), skipping the values that need quoting.");
+    eprintln!("  --trace          Report on stderr where each variable is set or overridden, in order,");
+    eprintln!("                   even with -s.");
+    eprintln!("  --log-json       Write errors, warnings and --trace reports on stderr as JSON");
+    eprintln!("                   objects with a level, a message and, if any, the file concerned.");
+    eprintln!("  --color WHEN     Color the ERROR, WARNING and TRACE of diagnostics always, never,");
+    eprintln!("                   or only if stderr is a terminal (auto). Default: auto");
+    eprintln!("  --summary        Report on stderr how many variables were set from how many files,");
+    eprintln!("                   even with -s.");
+    eprintln!("  --config FILE    Read default options from FILE, a TOML table of settings named");
+    eprintln!("                   after the options they stand for (silent = true for -s,");
+    eprintln!("                   array_separator = \",\" for --array-separator ,). The options of");
+    eprintln!("                   the command line override them, even the flags they contradict:");
+    eprintln!("                   --strict drops silent = true. format only applies with --print.");
+    eprintln!("                   Unknown settings are ignored with a warning. Empty for none.");
+    eprintln!("                   Default: ~/.envfrc, if it exists.");
+    eprintln!("  --project FILE   Read options from FILE, a TOML table whose files array lists the");
+    eprintln!("                   FILEs to read as with -f, whose command array, if any, is the");
+    eprintln!("                   COMMAND to run if none is given, and whose other settings are");
+    eprintln!("                   those of --config, which they override. The relative paths of");
+    eprintln!("                   files are read from FILE's directory. The options of the command");
+    eprintln!("                   line override those of FILE as those of --config, and its -f");
+    eprintln!("                   FILEs are read after those of FILE.");
+    eprintln!("  --dry-run        Instead of running COMMAND, print it on stderr along with the");
+    eprintln!("                   variables its environment would add (+), change (~) or remove (-).");
+    eprintln!("  --echo-command   Print COMMAND on stderr, quoted for a shell, just before running it.");
+    eprintln!("  --allow-empty-command");
+    eprintln!("                   If no COMMAND is given, run the shell named by SHELL (without");
+    eprintln!("                   --no-inherit), or else /bin/sh, instead of failing.");
+    eprintln!("  --validate       Instead of running COMMAND, load each file and print whether it is");
+    eprintln!("                   OK or why it isn't, then exit with status 1 if any isn't.");
+    eprintln!("                   COMMAND is then optional.");
+    eprintln!("  --parse-only     Like --validate, but only check that each file can be parsed,");
+    eprintln!("                   not that its variables can be set.");
+    eprintln!("  --explain KEY    Instead of running COMMAND, print a JSON object telling whether KEY");
+    eprintln!("                   is set for it and, if so, its value, where it comes from (a file,");
+    eprintln!("                   -e, --default or the environment) and which earlier values it");
+    eprintln!("                   overrides. Exit with status 1 if KEY isn't set. COMMAND is then");
+    eprintln!("                   optional.");
+    eprintln!("  --dump-config    Print on stderr how envf understood its options, before loading");
+    eprintln!("                   anything, then proceed as usual.");
+    eprintln!("  --mask           Write *** instead of values in warnings and errors. Keys are still");
+    eprintln!("                   shown, and the variables themselves are unaffected.");
+    eprintln!("  --chdir DIR      Run COMMAND in DIR. Relative FILE paths are still read from the");
+    eprintln!("                   current directory.");
+    eprintln!("  --user NAME[:GROUP]");
+    eprintln!("                   Run COMMAND as the user NAME, and as the group GROUP if given or");
+    eprintln!("                   else as NAME's primary group, with only NAME's supplementary");
+    eprintln!("                   groups. Both can be numeric IDs; a user ID with no entry needs");
+    eprintln!("                   GROUP. Unix only.");
+    eprintln!("  --spawn          Run COMMAND as a child process and exit with its status, instead of");
+    eprintln!("                   replacing envf with it. The child is terminated if envf dies,");
+    eprintln!("                   and receives the SIGINT, SIGTERM and SIGHUP sent to envf.");
+    eprintln!("  --chain          Split COMMAND at each --- argument into commands run in turn.");
+    eprintln!("  --timeout SECONDS");
+    eprintln!("                   Like --spawn, but send SIGTERM to COMMAND if it still runs after");
+    eprintln!("                   SECONDS, then SIGKILL 5 seconds later, and exit with status 124.");
+    eprintln!("                   Default: 0, for no timeout.");
+    eprintln!("  --prefix PREFIX  Prepend PREFIX to the name of every variable read from the sources");
+    eprintln!("                   that follow, up to the next --prefix.");
+    eprintln!("  --upper          Convert the name of every variable read from the files to uppercase.");
+    eprintln!("                   Same as --key-case upper.");
+    eprintln!("  --key-case CASE  Convert the name of every variable read from the files to upper or");
+    eprintln!("                   lower case, or to snake (my-key and myKey become MY_KEY). Names");
+    eprintln!("                   that become the same are reported. Default: none");
+    eprintln!("  --dots-to-underscores");
+    eprintln!("                   Replace the dots in the names of the variables read from the");
+    eprintln!("                   files, as in \"a.b\" = 1, with _, after --key-case. Names that");
+    eprintln!("                   become the same are reported.");
+    eprintln!("  --section NAME   Only read the variables of the [NAME] table of each file, as if they");
+    eprintln!("                   were at the top level. Files without such a table are rejected.");
+    eprintln!("  --separator SEP  Join the keys of nested tables with SEP, so that [a] b = 1");
+    eprintln!("                   becomes a_b=1. Also joins the keys of arrays of tables to their");
+    eprintln!("                   indices: [[a]] b = 1 becomes a_0_b=1. Inline tables and dotted");
+    eprintln!("                   keys are joined the same way: a = {{ b = 1 }} and a.b = 1 also");
+    eprintln!("                   become a_b=1. Default: _");
+    eprintln!("  --array-separator SEP");
+    eprintln!("                   Join the items of arrays of scalars with SEP. Default: :");
+    eprintln!("  --max-value-len BYTES");
+    eprintln!("                   Warn about the values longer than BYTES, which may be more than");
+    eprintln!("                   the system lets COMMAND be started with. Those that are known to");
+    eprintln!("                   be are an error, even with -s. 0 for no warning. Default: 65536");
+    eprintln!("  --float-format DECIMALS");
+    eprintln!("                   Write floats with exactly DECIMALS digits after the decimal point,");
+    eprintln!("                   so that 1.0 becomes 1.00 with 2. Integers are unaffected.");
+    eprintln!("                   Default: as many digits as needed, \"1.0\" becoming \"1\".");
+    eprintln!("  --bool-style STYLE");
+    eprintln!("                   Write booleans as true/false (true-false, the default), 1/0");
+    eprintln!("                   (one-zero) or yes/no (yes-no).");
+    eprintln!("  --int-radix RADIX");
+    eprintln!("                   Write integers in base 10 (dec, the default), 16 (hex), 8 (oct)");
+    eprintln!("                   or 2 (bin). Negative integers keep their sign, as in -1f.");
+    eprintln!("  --radix-prefix   With --int-radix, prefix integers with 0x, 0o or 0b, as in 0o755.");
+    eprintln!("                   Shells read integers in base 10, in base 16 after 0x and in base 8");
+    eprintln!("                   after another leading 0: the integers they would read as others are");
+    eprintln!("                   reported, such as 10 with --int-radix hex alone, read as ten.");
+    eprintln!("  --int-u64        Read the strings made of decimal digits, without leading zeros, as");
+    eprintln!("                   unsigned 64-bit integers, written and checked as integers are. Those");
+    eprintln!("                   too large for 64 bits are kept as they are, with a warning.");
+    eprintln!("  --merge-arrays   When a file sets a variable to an array that an earlier file also");
+    eprintln!("                   set to an array, append the items instead of replacing them.");
+    eprintln!("  --skip-nonscalar Skip the values that can't be converted into a string, such as");
+    eprintln!("                   arrays of arrays, with a warning, instead of rejecting the file.");
+    eprintln!("  --multi-doc      Split each file at the lines holding only ---, and read each part");
+    eprintln!("                   as a document of its own, the later ones overriding the earlier");
+    eprintln!("                   ones. Parts that can't be parsed are skipped with a warning.");
+    eprintln!("  --null-as-unset  Read the null values of JSON and YAML files as {{ unset = true }},");
+    eprintln!("                   removing their key, instead of rejecting the file. TOML has no");
+    eprintln!("                   null: use {{ unset = true }} there.");
+    eprintln!("  --no-expand-tilde");
+    eprintln!("                   Read a leading ~ in FILE, DIR and the like as is.");
+    eprintln!("  --no-skip-underscore");
+    eprintln!("                   Read the keys starting with _ like any other.");
+    eprintln!("  --datetime-format FORMAT");
+    eprintln!("                   Write datetimes as in RFC 3339 (rfc3339, the default) or as");
+    eprintln!("                   seconds since the Unix epoch (epoch). With epoch, datetimes");
+    eprintln!("                   without an offset are taken to be in UTC, with a warning.");
+    eprintln!("  --strict-args    Reject the following arguments starting with - that aren't");
+    eprintln!("                   options, instead of taking them as the start of COMMAND.");
+    eprintln!("  --               Stop reading options: what follows is COMMAND, even if it starts");
+    eprintln!("                   with -.");
+    eprintln!("  -h, --help       Display this message.");
+    eprintln!();
     eprintln!("Version: 1.0.0");
     eprintln!("Source: https://github.com/thilp/envf");
 }
 
+/// Whether diagnostics are written as JSON objects, with --log-json.
+static LOG_JSON: AtomicBool = AtomicBool::new(false);
+
+/// Whether the levels of diagnostics are colored, according to --color.
+static COLOR: AtomicBool = AtomicBool::new(false);
+
+/// The ANSI escape sequence coloring the name of `level`.
+fn level_color(level: &str) -> &'static str {
+    match level {
+        "error" => "\x1b[1;31m",
+        "warning" => "\x1b[1;33m",
+        _ => "\x1b[1;36m",
+    }
+}
+
+/// Writes `msg` on stderr at `level` (error, warning or trace), either after
+/// the level in capitals or, with --log-json, as a JSON object also holding
+/// the `file` the message is about, if any.
+fn emit(level: &str, file: Option<&str>, msg: &str) {
+    if LOG_JSON.load(Ordering::Relaxed) {
+        let mut object = serde_json::Map::new();
+        object.insert(String::from("level"), serde_json::Value::from(level));
+        if let Some(file) = file {
+            object.insert(String::from("file"), serde_json::Value::from(file));
+        }
+        object.insert(String::from("message"), serde_json::Value::from(msg));
+        eprintln!("{}", serde_json::Value::Object(object));
+    } else if COLOR.load(Ordering::Relaxed) {
+        eprintln!("{}{}\x1b[0m: {}", level_color(level), level.to_uppercase(), msg);
+    } else {
+        eprintln!("{}: {}", level.to_uppercase(), msg);
+    }
+}
+
 fn error_without_usage(msg: &str) -> ! {
-    eprintln!("ERROR: {}", msg);
+    emit("error", None, msg);
     process::exit(1);
 }
 
+/// Like [`error_without_usage`], followed by the usage text unless the error
+/// is written as JSON.
 fn error_with_usage(msg: &str) -> ! {
-    eprintln!("ERROR: {}", msg);
-    eprintln!("");
-    print_usage();
+    emit("error", None, msg);
+    if !LOG_JSON.load(Ordering::Relaxed) {
+        eprintln!();
+        print_usage();
+    }
     process::exit(1);
 }
 
 fn warning(msg: &str) {
-    eprintln!("WARNING: {}", msg);
+    emit("warning", None, msg);
+}
+
+/// Like [`error_without_usage`], for a message about the file at `path`.
+fn file_error(path: &str, msg: &str) -> ! {
+    emit("error", Some(path), msg);
+    process::exit(1);
+}
+
+/// Like [`warning`], for a message about the file at `path`.
+fn file_warning(path: &str, msg: &str) {
+    emit("warning", Some(path), msg);
+}
+
+/// Reports on what --trace follows, for the file at `path`.
+fn file_trace(path: &str, msg: &str) {
+    emit("trace", Some(path), msg);
 }
 
+#[derive(Debug)]
 struct Config {
-    files: Vec<String>,
+    sources: Vec<(Source, String)>,
+    environment: Option<String>,
+    layer: Option<String>,
     silent: bool,
-    command: Vec<String>,
+    strict: bool,
+    fail_on_skip: bool,
+    on_missing_file: MissingFilePolicy,
+    keep_going: bool,
+    dump_config: bool,
+    validate: bool,
+    parse_only: bool,
+    dry_run: bool,
+    echo_command: bool,
+    allow_empty_command: bool,
+    explain: Option<String>,
+    /// The --config file; None for the default one, and empty for none.
+    rc_file: Option<String>,
+    project: Option<String>,
+    summary: bool,
+    require_nonempty: bool,
+    allow_unset: bool,
+    print: Option<PrintFormat>,
+    /// Where -o has --print write the variables, instead of stdout.
+    output: Option<String>,
+    /// The snapshot --diff compares the variables with, instead of running.
+    diff: Option<String>,
+    trace: bool,
+    log_json: bool,
+    color: ColorMode,
+    chdir: Option<String>,
+    expand_tilde: bool,
+    defaults_only: bool,
+    no_override: bool,
+    max_value_len: usize,
+    user: Option<String>,
+    spawn: bool,
+    timeout: Option<Duration>,
+    retry: u32,
+    retry_delay: Duration,
+    /// The commands to run in turn, separated by --- on the command line.
+    command: Vec<Vec<String>>,
+    overrides: Vec<(String, String)>,
+    defaults: Vec<(String, String)>,
+    renames: Vec<(String, String)>,
+    map_files: Vec<String>,
+    unset: Vec<String>,
+    append_path: Vec<String>,
+    only: Vec<String>,
+    exclude: Vec<String>,
+    require: Vec<String>,
+    clear: bool,
+    no_inherit: bool,
+    unused_prefix: Option<String>,
+    key_case: KeyCase,
+    dots_to_underscores: bool,
+    load: LoadOptions,
+}
+
+impl Config {
+    /// Whether envf is only asked about the variables, so that COMMAND is
+    /// optional.
+    fn queries(&self) -> bool {
+        self.print.is_some()
+            || self.validate
+            || self.parse_only
+            || self.explain.is_some()
+            || self.diff.is_some()
+    }
+
+    /// Whether the current environment is passed to the command, and thus
+    /// taken into account by --default, --require and ${NAME} references.
+    fn inherits(&self) -> bool {
+        !self.clear && !self.no_inherit
+    }
+
+    /// Describes `err`, without the values it mentions if --mask is given.
+    fn describe(&self, err: &EnvfError) -> String {
+        if self.load.mask {
+            err.masked().to_string()
+        } else {
+            err.to_string()
+        }
+    }
+}
+
+/// A place variables are read from, in the order given on the command line.
+#[derive(Debug)]
+enum Source {
+    /// A file or glob pattern; if `required`, failing to load it is fatal.
+    /// Parsed as `format`, if any, instead of according to its extension.
+    File {
+        path: String,
+        required: bool,
+        format: Option<FileFormat>,
+    },
+    Dir(String),
+    Dotenv(String),
+    EnvVar(String),
+    Url(String),
+    /// A manifest listing files to read as with -f, parsed as `format`.
+    /// Replaced by those files before anything is loaded.
+    FileList {
+        path: String,
+        format: Option<FileFormat>,
+    },
+}
+
+/// What --env-file-format forces the files to be parsed as.
+#[derive(Clone, Copy, Debug)]
+enum FileFormat {
+    Document(Format),
+    Dotenv,
+}
+
+/// How --key-case converts the names of the variables read from the files.
+#[derive(Clone, Copy, Debug)]
+enum KeyCase {
+    None,
+    Upper,
+    Lower,
+    Snake,
+}
+
+impl KeyCase {
+    fn apply(self, k: &str) -> String {
+        match self {
+            KeyCase::None => String::from(k),
+            KeyCase::Upper => k.to_uppercase(),
+            KeyCase::Lower => k.to_lowercase(),
+            KeyCase::Snake => {
+                let chars: Vec<char> = k.chars().collect();
+                let mut snake = String::new();
+                for (i, &c) in chars.iter().enumerate() {
+                    if c == '-' {
+                        snake.push('_');
+                        continue;
+                    }
+                    // A word starts at an uppercase letter following a lowercase
+                    // one or a digit (myKey), or ending a run of them and followed
+                    // by a lowercase one (HTTPServer).
+                    let prev = if i > 0 { Some(chars[i - 1]) } else { None };
+                    let next = chars.get(i + 1);
+                    if c.is_uppercase() {
+                        if let Some(prev) = prev {
+                            let after_lower = prev.is_lowercase() || prev.is_ascii_digit();
+                            let ends_run =
+                                prev.is_uppercase() && next.is_some_and(|n| n.is_lowercase());
+                            if after_lower || ends_run {
+                                snake.push('_');
+                            }
+                        }
+                    }
+                    snake.extend(c.to_uppercase());
+                }
+                snake
+            }
+        }
+    }
+}
+
+/// How --print and its variants render the variables.
+#[derive(Clone, Copy, Debug)]
+enum PrintFormat {
+    Lines,
+    Export(QuoteStyle),
+    Json,
+}
+
+/// What --on-missing-file does about the files that don't exist.
+#[derive(Clone, Copy, Debug)]
+enum MissingFilePolicy {
+    Error,
+    /// As for other files that can't be loaded: warn, or abort with --strict.
+    Warn,
+    Ignore,
+}
+
+/// When --color colors diagnostics.
+#[derive(Clone, Copy, Debug)]
+enum ColorMode {
+    /// If stderr is a terminal.
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorMode {
+    fn enabled(self) -> bool {
+        match self {
+            ColorMode::Auto => io::stderr().is_terminal(),
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+        }
+    }
+}
+
+/// How --export quotes values.
+#[derive(Clone, Copy, Debug)]
+enum QuoteStyle {
+    /// Nothing is expanded.
+    Single,
+    /// `$` is expanded by the shell; `"`, `\` and backticks are escaped.
+    Double,
+    /// Only for values that are already shell words.
+    None,
+}
+
+impl QuoteStyle {
+    /// Quotes `value` for a POSIX shell, unless it can't be with this style.
+    fn quote(self, value: &str) -> Option<String> {
+        match self {
+            QuoteStyle::Single => Some(format!("'{}'", value.replace('\'', "'\\''"))),
+            QuoteStyle::Double => {
+                let mut quoted = String::from("\"");
+                for c in value.chars() {
+                    if matches!(c, '"' | '\\' | '`') {
+                        quoted.push('\\');
+                    }
+                    quoted.push(c);
+                }
+                quoted.push('"');
+                Some(quoted)
+            }
+            QuoteStyle::None => {
+                let safe = |c: char| c.is_ascii_alphanumeric() || "_@%+=:,./-".contains(c);
+                if !value.is_empty() && value.chars().all(safe) {
+                    Some(String::from(value))
+                } else {
+                    None
+                }
+            }
+        }
+    }
 }
 
 fn main() {
-    let config = match parse_args(env::args().skip(1)) {
+    let args: Vec<String> = env::args().skip(1).collect();
+    COLOR.store(ColorMode::Auto.enabled(), Ordering::Relaxed);
+    // So that the errors of the options themselves are written as JSON too.
+    let log_json = args.iter().take_while(|arg| *arg != "--").any(|arg| arg == "--log-json");
+    LOG_JSON.store(log_json, Ordering::Relaxed);
+    let mut config = parsed_config(parse_args(args.iter().cloned()));
+    LOG_JSON.store(config.log_json, Ordering::Relaxed);
+    COLOR.store(config.color.enabled(), Ordering::Relaxed);
+    let rc_args = rc_arguments(&config);
+    let (project_args, project_command) = project_arguments(&config);
+    if !rc_args.is_empty() || !project_args.is_empty() {
+        let command = if !config.command.is_empty() {
+            vec![]
+        } else if args.iter().any(|arg| arg == "--") {
+            project_command
+        } else {
+            let mut command = vec!["--".to_string()];
+            command.extend(project_command);
+            command
+        };
+        let args = rc_args
+            .into_iter()
+            .chain(iter::once(DEFAULTS_END.to_string()))
+            .chain(project_args)
+            .chain(iter::once(DEFAULTS_END.to_string()))
+            .chain(args)
+            .chain(command);
+        config = parsed_config(parse_args(args));
+    }
+    // Only now that the command of the --project file is known.
+    if config.command.is_empty() && config.allow_empty_command {
+        match default_shell(!config.no_inherit) {
+            Some(shell) => config.command.push(vec![shell]),
+            None => error_with_usage(
+                "--allow-empty-command: $SHELL isn't set and there is no /bin/sh to run.",
+            ),
+        }
+    }
+    if config.command.is_empty() && !config.queries() {
+        error_with_usage("No command to execute was provided.");
+    }
+    LOG_JSON.store(config.log_json, Ordering::Relaxed);
+    COLOR.store(config.color.enabled(), Ordering::Relaxed);
+    if config.dump_config {
+        eprintln!("{:#?}", config);
+    }
+    if let (Some(prefix), false) = (&config.unused_prefix, config.silent) {
+        warning(&format!("--prefix {} is not followed by any file; it has no effect", prefix));
+    }
+    if let Some(path) = &config.output {
+        let dir = match Path::new(path).parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        if !dir.is_dir() {
+            error_without_usage(&format!(
+                "Can't write to {}: {} isn't a directory",
+                path,
+                dir.display()
+            ));
+        }
+    }
+    config.sources = expand_sources(mem::take(&mut config.sources), &config);
+    if config.validate || config.parse_only {
+        let mut invalid = false;
+        for (source, prefix) in &config.sources {
+            for (path, result) in load_source(source, &config) {
+                let problem = match result {
+                    Err(err) => Some(config.describe(&err)),
+                    Ok(_) if config.parse_only => None,
+                    Ok(m) => {
+                        let mut keys: Vec<&String> = m.keys().collect();
+                        keys.sort();
+                        keys.into_iter().find_map(|k| {
+                            let v = match &m[k] {
+                                Value::String(v) | Value::Array(v) | Value::Template(v) => v,
+                                Value::Unset => "",
+                            };
+                            unusable_variable(&format!("{}{}", prefix, k), v)
+                        })
+                    }
+                };
+                match problem {
+                    None => println!("{}: OK", path),
+                    Some(problem) => {
+                        invalid = true;
+                        println!("{}: {}", path, problem);
+                    }
+                }
+            }
+        }
+        process::exit(if invalid { 1 } else { 0 });
+    }
+    let mut loader = Loader::new(&config);
+    let mut loaded = 0;
+    let mut skipped = 0;
+    let mut failures = vec![];
+    for (source, prefix) in &config.sources {
+        let required = matches!(source, Source::File { required: true, .. });
+        for (path, result) in load_source(source, &config) {
+            match result {
+                Err(err) => {
+                    let policy = if err.is_not_found() {
+                        config.on_missing_file
+                    } else {
+                        MissingFilePolicy::Warn
+                    };
+                    // As if the file had never been given.
+                    if !required && matches!(policy, MissingFilePolicy::Ignore) {
+                        continue;
+                    }
+                    loaded += 1;
+                    skipped += 1;
+                    let msg = format!("{} ignored: {}", path, config.describe(&err));
+                    if required || matches!(policy, MissingFilePolicy::Error) || config.strict {
+                        file_error(&path, &msg);
+                    } else if config.keep_going {
+                        failures.push((path, msg));
+                    } else if !config.silent {
+                        file_warning(&path, &msg);
+                    }
+                }
+                Ok(m) => {
+                    loaded += 1;
+                    loader.add_file(&path, prefix, m);
+                }
+            }
+        }
+    }
+    if config.defaults_only && config.inherits() {
+        loader.keep_inherited();
+    }
+    for (k, v) in &config.overrides {
+        loader.set(k.clone(), v.clone(), "-e");
+    }
+    for (k, v) in &config.defaults {
+        if !loader.merge.is_set(k) && (!config.inherits() || env::var_os(k).is_none()) {
+            loader.set(k.clone(), v.clone(), "--default");
+        }
+    }
+    loader.compute();
+    if config.require_nonempty && loader.merge.map.is_empty() {
+        error_without_usage(&format!(
+            "No variables were set ({} of {} files couldn't be processed)",
+            skipped, loaded
+        ));
+    }
+    let Merge {
+        map,
+        origins,
+        overridden,
+        ..
+    } = loader.merge;
+    let mut map = match interpolate(&map, config.allow_unset, config.inherits()) {
+        Err(err) => error_without_usage(&config.describe(&err)),
+        Ok(m) => m,
+    };
+    for key in &config.append_path {
+        if let (Some(loaded), Some(inherited)) = (
+            map.get_mut(key),
+            env::var_os(key).filter(|_| config.inherits()),
+        ) {
+            let inherited = inherited.to_string_lossy();
+            // An empty entry would stand for the current directory.
+            if loaded.is_empty() {
+                *loaded = inherited.into_owned();
+            } else if !inherited.is_empty() {
+                *loaded = format!("{}{}{}", inherited, PATH_SEPARATOR, loaded);
+            }
+        }
+    }
+    let mut renames = vec![];
+    for path in &config.map_files {
+        renames.extend(read_map_file(path, &config));
+    }
+    renames.extend(config.renames.iter().cloned());
+    for (old, new) in &renames {
+        if let Some(v) = map.remove(old) {
+            if !is_shell_identifier(new) && !config.silent {
+                warning(&format!("{:?} is not a conventional variable name", new));
+            }
+            if map.contains_key(new) && !config.silent {
+                warning(&format!("renaming {} overwrites {}", old, new));
+            }
+            map.insert(new.clone(), v);
+        }
+    }
+    if !config.only.is_empty() {
+        map.retain(|k, _| config.only.iter().any(|prefix| k.starts_with(prefix)));
+    }
+    for key in &config.exclude {
+        map.remove(key);
+    }
+    let missing: Vec<&str> = config
+        .require
+        .iter()
+        .filter(|key| {
+            config.unset.contains(key)
+                || !(map.contains_key(*key) || (config.inherits() && env::var_os(key).is_some()))
+        })
+        .map(|key| key.as_str())
+        .collect();
+    if !missing.is_empty() {
+        error_without_usage(&format!(
+            "Missing required variables: {}",
+            missing.join(", ")
+        ));
+    }
+    if config.summary {
+        eprintln!(
+            "Loaded {} from {} ({} ignored)",
+            count(map.len(), "variable"),
+            count(loaded - skipped, "file"),
+            skipped
+        );
+    }
+    if !failures.is_empty() && !config.silent {
+        warning(&format!("{} couldn't be loaded:", count(failures.len(), "file")));
+        for (path, msg) in &failures {
+            file_warning(path, &format!("  {}", msg));
+        }
+    }
+    if let Some(key) = &config.explain {
+        let origin = renames
+            .iter()
+            .rev()
+            .find(|(_, new)| new == key)
+            .map_or(key, |(old, _)| old);
+        let set = !config.unset.contains(key);
+        let value = map
+            .get(key)
+            .cloned()
+            .map(|v| (v, origins.get(origin).map_or("", String::as_str)))
+            .or_else(|| {
+                env::var(key)
+                    .ok()
+                    .filter(|_| config.inherits())
+                    .map(|v| (v, "environment"))
+            })
+            .filter(|_| set);
+        explain(key, value, overridden.get(origin), config.load.mask);
+    }
+    if let Some(format) = config.print {
+        for key in &config.unset {
+            map.remove(key);
+        }
+        let written = match &config.output {
+            None => print_env(&mut io::stdout().lock(), &map, format, config.silent),
+            Some(path) => write_env(path, &map, format, config.silent),
+        };
+        if let Err(err) = written {
+            let target = config.output.as_deref().unwrap_or("the standard output");
+            error_without_usage(&format!("Couldn't write the variables to {}: {}", target, err));
+        }
+        process::exit(if config.fail_on_skip && skipped > 0 { 1 } else { 0 });
+    }
+    if let Some(path) = &config.diff {
+        for key in &config.unset {
+            map.remove(key);
+        }
+        let expected = match fs::read_to_string(path) {
+            Err(err) => error_without_usage(&format!("Couldn't read {}: {}", path, err)),
+            Ok(expected) => expected,
+        };
+        let mut actual = vec![];
+        if let Err(err) = print_env(&mut actual, &map, PrintFormat::Lines, config.silent) {
+            error_without_usage(&format!("Couldn't render the variables: {}", err));
+        }
+        let same = diff_env(path, &expected, &String::from_utf8_lossy(&actual));
+        process::exit(if same { 0 } else { 1 });
+    }
+    if let Some(dir) = &config.chdir {
+        if !Path::new(dir).is_dir() {
+            error_without_usage(&format!("Can't run the command in {}: not a directory", dir));
+        }
+    }
+    let user = config.user.as_ref().map(|spec| match user_ids(spec) {
+        Err(msg) => error_without_usage(&msg),
+        Ok(ids) => ids,
+    });
+    check_value_lengths(&config, &map);
+    if config.dry_run {
+        describe_run(&config, &map);
+        process::exit(0);
+    }
+    for argv in &config.command {
+        check_command_path(&config, argv, &map);
+    }
+    if config.echo_command {
+        eprintln!("{}", command_line(&config));
+    }
+    let (argv, err) = run_command(&config, &map, user.as_ref());
+    error_without_usage(&format!("Couldn't execute command {:?}: {}", argv, err));
+}
+
+fn parsed_config(result: ArgParseResult) -> Config {
+    match result {
         ArgParseResult::Help => {
             print_usage();
             process::exit(0);
         },
         ArgParseResult::Err(s) => error_with_usage(s),
-        ArgParseResult::Config(c) => c,
+        ArgParseResult::UnknownOption(arg) => {
+            error_with_usage(&format!("Unknown option {} (see --strict-args)", arg))
+        }
+        ArgParseResult::Config(c) => *c,
+    }
+}
+
+/// The settings a --config file can hold, with the option each stands for and
+/// whether that option is a flag, set by `true`, rather than takes a value.
+const RC_SETTINGS: &[(&str, &str, bool)] = &[
+    ("allow_empty_command", "--allow-empty-command", true),
+    ("allow_unset", "--allow-unset", true),
+    ("array_separator", "--array-separator", false),
+    ("bool_style", "--bool-style", false),
+    ("chain", "--chain", true),
+    ("color", "--color", false),
+    ("clear", "--clear", true),
+    ("datetime_format", "--datetime-format", false),
+    ("defaults_only", "--defaults-only", true),
+    ("dots_to_underscores", "--dots-to-underscores", true),
+    ("echo_command", "--echo-command", true),
+    ("env", "--env", false),
+    ("env_file_format", "--env-file-format", false),
+    ("fail_on_skip", "--fail-on-skip", true),
+    ("float_format", "--float-format", false),
+    ("format", "--format", false),
+    ("int_radix", "--int-radix", false),
+    ("int_u64", "--int-u64", true),
+    ("keep_going", "--keep-going", true),
+    ("key_case", "--key-case", false),
+    ("layer", "--layer", false),
+    ("log_json", "--log-json", true),
+    ("mask", "--mask", true),
+    ("max_value_len", "--max-value-len", false),
+    ("merge_arrays", "--merge-arrays", true),
+    ("multi_doc", "--multi-doc", true),
+    ("no_expand_tilde", "--no-expand-tilde", true),
+    ("no_inherit", "--no-inherit", true),
+    ("no_override", "--no-override", true),
+    ("no_skip_underscore", "--no-skip-underscore", true),
+    ("null_as_unset", "--null-as-unset", true),
+    ("on_missing_file", "--on-missing-file", false),
+    ("prefix", "--prefix", false),
+    ("radix_prefix", "--radix-prefix", true),
+    ("require_nonempty", "--require-nonempty", true),
+    ("retry", "--retry", false),
+    ("retry_delay", "--retry-delay", false),
+    ("section", "--section", false),
+    ("separator", "--separator", false),
+    ("silent", "-s", true),
+    ("skip_nonscalar", "--skip-nonscalar", true),
+    ("spawn", "--spawn", true),
+    ("strict", "--strict", true),
+    ("strict_args", "--strict-args", true),
+    ("summary", "--summary", true),
+    ("timeout", "--timeout", false),
+    ("trace", "--trace", true),
+    ("upper", "--upper", true),
+];
+
+/// Turns the settings of the --config file, ~/.envfrc by default, into the
+/// options they stand for, to be parsed before those of the command line.
+fn rc_arguments(config: &Config) -> Vec<String> {
+    let (path, required) = match &config.rc_file {
+        Some(path) if path.is_empty() => return vec![],
+        Some(path) => (path.clone(), true),
+        None => match env::var_os("HOME") {
+            None => return vec![],
+            Some(home) => (
+                Path::new(&home).join(".envfrc").to_string_lossy().into_owned(),
+                false,
+            ),
+        },
+    };
+    let table = match fs::read_to_string(&path) {
+        Err(err) if !required && err.kind() == io::ErrorKind::NotFound => return vec![],
+        Err(err) => file_error(&path, &format!("Could not read {}: {}", path, err)),
+        Ok(body) => match toml::from_str::<toml::value::Table>(&body) {
+            Err(err) => file_error(&path, &format!("{}: {}", path, err)),
+            Ok(table) => table,
+        },
     };
-    let mut map = EnvMap::new();
-    for path in config.files {
-        match read_env_file(&path) {
-            Err(msg) => {
-                if !config.silent {
-                    warning(&format!("{} ignored: {}", path, msg));
+    settings_arguments(&path, table)
+}
+
+/// Turns the settings of `table`, read from the file at `path`, into the
+/// options they stand for.
+fn settings_arguments(path: &str, table: toml::value::Table) -> Vec<String> {
+    let mut args = vec![];
+    for (key, value) in table {
+        match (RC_SETTINGS.iter().find(|(k, _, _)| *k == key), value) {
+            (None, _) => file_warning(path, &format!("{}: unknown setting {} ignored", path, key)),
+            (Some((_, option, true)), toml::Value::Boolean(set)) => {
+                if set {
+                    args.push(option.to_string());
                 }
             }
-            Ok(m) => {
-                for (k, v) in m {
-                    map.insert(k, v);
-                }
+            (Some((_, option, false)), toml::Value::String(s)) => {
+                args.push(format!("{}={}", option, s))
             }
+            (Some((_, option, false)), v @ (toml::Value::Integer(_) | toml::Value::Float(_))) => {
+                args.push(format!("{}={}", option, v))
+            }
+            (Some(_), v) => file_warning(
+                path,
+                &format!("{}: invalid value for {} ignored: {}", path, key, v),
+            ),
         }
     }
-    let err = process::Command::new(&config.command[0])
-        .args(config.command.iter().skip(1).collect::<Vec<&String>>())
-        .envs(&map)
-        .exec();
-    error_without_usage(&format!(
-        "Couldn't execute command {:?}: {}",
-        config.command, err
-    ));
+    args
 }
 
-enum ArgParseResult {
-    Config(Config),
-    Err(&'static str),
-    Help,
+/// Turns the --project file, if any, into the options it stands for: -f for
+/// each of its files, then its settings, as in a --config file. Also returns
+/// its command, if it has one.
+fn project_arguments(config: &Config) -> (Vec<String>, Vec<String>) {
+    let path = match &config.project {
+        None => return (vec![], vec![]),
+        Some(path) => path,
+    };
+    let mut table = match fs::read_to_string(path) {
+        Err(err) => file_error(path, &format!("Could not read {}: {}", path, err)),
+        Ok(body) => match toml::from_str::<toml::value::Table>(&body) {
+            Err(err) => file_error(path, &format!("{}: {}", path, err)),
+            Ok(table) => table,
+        },
+    };
+    let files = match table.remove("files").map(string_array) {
+        None => file_error(path, &format!("{}: files is missing", path)),
+        Some(None) => file_error(path, &format!("{}: files must be an array of strings", path)),
+        Some(Some(files)) => files,
+    };
+    let command = match table.remove("command").map(string_array) {
+        None => vec![],
+        Some(Some(command)) if !command.is_empty() => command,
+        Some(_) => file_error(
+            path,
+            &format!("{}: command must be a non-empty array of strings", path),
+        ),
+    };
+    let dir = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+    let mut args: Vec<String> = files
+        .into_iter()
+        .flat_map(|file| ["-f".to_string(), project_file(dir, &file)])
+        .collect();
+    args.extend(settings_arguments(path, table));
+    (args, command)
 }
 
-fn parse_args(args: impl Iterator<Item = String>) -> ArgParseResult {
-    let mut files: Vec<String> = vec![];
-    let mut silent = false;
-    let mut args = args.peekable();
-    loop {
-        match args.peek() {
-            None => break,
-            Some(arg) => {
-                if arg == "-h" || arg == "--help" {
-                    return ArgParseResult::Help;
-                } else if arg == "-s" {
-                    silent = true;
-                } else if arg == "-f" {
-                    args.next();
-                    match args.peek() {
-                        None => return ArgParseResult::Err("Trailing -f"),
-                        Some(path) => files.push(path.to_string()),
-                    }
-                } else if arg.starts_with("-f=") {
-                    files.push(arg[3..].to_string());
-                } else {
-                    break;
+/// The path of `file`, listed by the --project file of `dir`, relative to it
+/// unless it starts with a variable or a ~, which are expanded later on.
+fn project_file(dir: &Path, file: &str) -> String {
+    let (required, path) = match file.strip_prefix('!') {
+        Some(path) => ("!", path),
+        None => ("", file),
+    };
+    if path == STDIN_PATH || path.starts_with(['$', '~']) {
+        return String::from(file);
+    }
+    format!("{}{}", required, dir.join(path).to_string_lossy())
+}
+
+/// The strings of `value`, if it is an array of strings.
+fn string_array(value: toml::Value) -> Option<Vec<String>> {
+    match value {
+        toml::Value::Array(items) => items
+            .into_iter()
+            .map(|item| match item {
+                toml::Value::String(s) => Some(s),
+                _ => None,
+            })
+            .collect(),
+        _ => None,
+    }
+}
+
+/// The name of the variable the key `k` of a file stands for, according to
+/// --key-case and --dots-to-underscores.
+fn convert_key(config: &Config, k: &str) -> String {
+    let converted = config.key_case.apply(k);
+    if config.dots_to_underscores {
+        converted.replace('.', "_")
+    } else {
+        converted
+    }
+}
+
+/// Why `k` can't be set to `v` in an environment, if it can't.
+fn unusable_variable(k: &str, v: &str) -> Option<String> {
+    if k.is_empty() || k.contains(['=', '\0']) {
+        Some(format!("{:?} can't be used as a variable name", k))
+    } else if v.contains('\0') {
+        Some(format!("value for {} contains a NUL byte", k))
+    } else {
+        None
+    }
+}
+
+/// Separates the directories of PATH-like variables.
+const PATH_SEPARATOR: char = if cfg!(windows) { ';' } else { ':' };
+
+/// Writes `n` followed by `noun`, in the plural unless `n` is 1.
+fn count(n: usize, noun: &str) -> String {
+    if n == 1 {
+        format!("1 {}", noun)
+    } else {
+        format!("{} {}s", n, noun)
+    }
+}
+
+fn load_source(source: &Source, config: &Config) -> Vec<(String, FileMapOrError)> {
+    let options = &config.load;
+    match source {
+        Source::File {
+            path: pattern,
+            format,
+            ..
+        } if is_glob(pattern) => match expand_glob(pattern) {
+            Err(err) => vec![(pattern.clone(), Err(err))],
+            Ok(paths) => paths
+                .into_iter()
+                .map(|path| load_file(path, *format, config))
+                .collect(),
+        },
+        Source::File { path, format, .. } => {
+            let mut loaded = vec![load_file(path.clone(), *format, config)];
+            if let Some(name) = &config.environment {
+                let env_path = layer_path(path, name);
+                let exists = Path::new(&env_path).is_file();
+                if path != STDIN_PATH && (exists || config.strict) {
+                    loaded.push(load_file(env_path, *format, config));
+                }
+            }
+            if let Some(name) = &config.layer {
+                let layer = layer_path(path, name);
+                if path != STDIN_PATH && Path::new(&layer).is_file() {
+                    loaded.push(load_file(layer, *format, config));
                 }
             }
+            loaded
         }
-        args.next();
+        Source::Dir(dir) => {
+            match with_retries(config, || read_env_dir(dir, options).map_err(EnvfError::ListDir)) {
+                Err(err) => vec![(dir.clone(), Err(err))],
+                Ok(loaded) => loaded,
+            }
+        }
+        Source::EnvVar(name) => {
+            let label = format!("${}", name);
+            let result = match env::var(name) {
+                Ok(body) if !body.is_empty() => parse_env(&label, &body, options),
+                _ => Err(EnvfError::EmptyVariable),
+            };
+            vec![(label, result)]
+        }
+        Source::Dotenv(path) => vec![load_dotenv(path.clone(), config)],
+        Source::Url(url) => {
+            vec![(url.clone(), with_retries(config, || read_env_url(url, options)))]
+        }
+        Source::FileList { .. } => unreachable!("file lists are expanded beforehand"),
+    }
+}
+
+/// Expands the variables in `path` and, unless --no-expand-tilde is given, a
+/// leading ~ or ~USER, exiting with an error if that fails.
+fn expand_file_path(path: &str, config: &Config) -> String {
+    let expanded = match expand_path(path, !config.no_inherit) {
+        Err(err) => error_without_usage(&err.to_string()),
+        Ok(expanded) => expanded,
+    };
+    if !config.expand_tilde {
+        return expanded;
     }
-    if files.len() == 0 {
-        files.push(".env.toml".to_string());
+    match expand_tilde(&expanded, !config.no_inherit) {
+        Err(msg) => error_without_usage(&msg),
+        Ok(expanded) => expanded,
     }
-    let cmd: Vec<String> = args.collect();
-    if cmd.len() == 0 {
-        ArgParseResult::Err("No command to execute was provided.")
+}
+
+/// Replaces a leading ~ in `path` with the home directory of the current user
+/// (from HOME, if `inherit`), and a leading ~USER with that of USER.
+fn expand_tilde(path: &str, inherit: bool) -> Result<String, String> {
+    let rest = match path.strip_prefix('~') {
+        Some(rest) => rest,
+        None => return Ok(String::from(path)),
+    };
+    let (name, tail) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    let home = if name.is_empty() {
+        env::var_os("HOME")
+            .filter(|home| inherit && !home.is_empty())
+            .map(|home| home.to_string_lossy().into_owned())
+            .or_else(|| home_dir(None))
     } else {
-        ArgParseResult::Config(Config {
-            files: files,
-            silent: silent,
-            command: cmd,
-        })
+        home_dir(Some(name))
+    };
+    match home {
+        Some(home) => Ok(format!("{}{}", home, tail)),
+        None if name.is_empty() => Err(format!("Can't expand ~ in {}: no home directory", path)),
+        None => Err(format!("Can't expand ~{} in {}: unknown user {}", name, path, name)),
     }
 }
 
-fn read_env_file(path: &str) -> EnvMapOrError {
-    match fs::read_to_string(path) {
-        Err(err) => Err(format!("Could not read contents: {}", err.description())),
-        Ok(body) => match body.parse::<toml::Value>() {
-            Err(err) => Err(format!("Invalid TOML: {}", err)),
-            Ok(doc) => match doc.try_into::<toml::value::Table>() {
-                Err(err) => Err(format!("Unexpected format: {}", err.description())),
-                Ok(table) => table_into_env_map(&table),
-            },
-        },
+/// The home directory of the user called `name`, or else of the current user.
+#[cfg(unix)]
+fn home_dir(name: Option<&str>) -> Option<String> {
+    let name = match name {
+        Some(name) => Some(CString::new(name).ok()?),
+        None => None,
+    };
+    // Safety: as in lookup_user.
+    unsafe {
+        let entry = match &name {
+            Some(name) => libc::getpwnam(name.as_ptr()),
+            None => libc::getpwuid(libc::getuid()),
+        };
+        if entry.is_null() || (*entry).pw_dir.is_null() {
+            None
+        } else {
+            Some(CStr::from_ptr((*entry).pw_dir).to_string_lossy().into_owned())
+        }
     }
 }
 
-fn table_into_env_map(table: &toml::value::Table) -> EnvMapOrError {
-    table.iter().fold(Ok(EnvMap::new()), add_field)
+#[cfg(not(unix))]
+fn home_dir(_name: Option<&str>) -> Option<String> {
+    None
 }
 
-fn add_field(z: EnvMapOrError, (k, v): (&String, &toml::Value)) -> EnvMapOrError {
-    match z {
-        Err(_) => z,
-        Ok(m) => match stringify(v) {
-            Some(s) => {
-                let mut n = m.clone();
-                n.insert(String::from(k), s);
-                Ok(n)
+/// Expands the file paths of `sources`, and replaces each --file-list with the
+/// sources for the files it lists, exiting with an error if it can't be read.
+fn expand_sources(sources: Vec<(Source, String)>, config: &Config) -> Vec<(Source, String)> {
+    let mut expanded = vec![];
+    for (mut source, prefix) in sources {
+        let (path, format) = match &mut source {
+            Source::FileList { path, format } => (path.clone(), *format),
+            Source::File { path, .. } | Source::Dir(path) | Source::Dotenv(path) => {
+                *path = expand_file_path(path, config);
+                expanded.push((source, prefix));
+                continue;
             }
-            None => Err(format!(
-                "value for {} ({:?}) can't be converted into a string",
-                k, v
-            )),
-        },
+            // URLs can hold a literal $ or ~, as in ?$filter=.
+            Source::Url(_) | Source::EnvVar(_) => {
+                expanded.push((source, prefix));
+                continue;
+            }
+        };
+        let path = expand_file_path(&path, config);
+        let body = match fs::read_to_string(&path) {
+            Err(err) => error_without_usage(&format!("Couldn't read file list {}: {}", path, err)),
+            Ok(body) => body,
+        };
+        let dir = Path::new(&path).parent().unwrap_or_else(|| Path::new(""));
+        for line in body.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (required, file) = match line.strip_prefix('!') {
+                Some(file) => ("!", file),
+                None => ("", line),
+            };
+            let file = if file == STDIN_PATH {
+                String::from(STDIN_PATH)
+            } else {
+                dir.join(expand_file_path(file, config)).to_string_lossy().into_owned()
+            };
+            let file = format!("{}{}", required, file);
+            expanded.push((file_source(&file, format), prefix.clone()));
+        }
+    }
+    if expanded.iter().filter(|(source, _)| reads_stdin(source)).count() > 1 {
+        error_without_usage(STDIN_ERROR);
     }
+    expanded
 }
 
-fn stringify(v: &toml::Value) -> Option<String> {
-    match v {
-        toml::value::Value::String(s) => Some(String::from(s)),
-        toml::value::Value::Integer(x) => Some(format!("{}", x)),
-        toml::value::Value::Float(x) => Some(format!("{}", x)),
-        toml::value::Value::Boolean(x) => Some(format!("{}", x)),
-        toml::value::Value::Datetime(x) => Some(format!("{}", x)),
-        _ => None,
+/// Whether `source` is read from the standard input.
+fn reads_stdin(source: &Source) -> bool {
+    matches!(source, Source::File { path, .. } | Source::Dotenv(path) if path == STDIN_PATH)
+}
+
+const STDIN_ERROR: &str = "The standard input (-f -) can only be read once.";
+
+/// Reads the file at `path` as `format`, or according to its extension.
+fn load_file(
+    path: String,
+    format: Option<FileFormat>,
+    config: &Config,
+) -> (String, FileMapOrError) {
+    let result = match format {
+        None => with_retries(config, || read_env_file(&path, &config.load)),
+        Some(FileFormat::Document(format)) => {
+            with_retries(config, || read_env_file_as(&path, format, &config.load))
+        }
+        Some(FileFormat::Dotenv) => return load_dotenv(path, config),
+    };
+    (path, result)
+}
+
+/// Calls `read` again, up to --retry times and after --retry-delay, for as
+/// long as it fails to read anything, rather than reads something invalid.
+fn with_retries<T>(
+    config: &Config,
+    read: impl Fn() -> Result<T, EnvfError>,
+) -> Result<T, EnvfError> {
+    let mut result = read();
+    for _ in 0..config.retry {
+        match &result {
+            Err(err) if err.is_read_failure() => {}
+            _ => break,
+        }
+        thread::sleep(config.retry_delay);
+        result = read();
+    }
+    result
+}
+
+fn load_dotenv(path: String, config: &Config) -> (String, FileMapOrError) {
+    match with_retries(config, || read_dotenv_file(&path)) {
+        Err(err) => (path, Err(err)),
+        Ok((m, malformed)) => {
+            if !config.silent {
+                for line in malformed {
+                    file_warning(&path, &format!("{}: line {} ignored: not KEY=VALUE", path, line));
+                }
+            }
+            (path, Ok(m.into_iter().map(|(k, v)| (k, Value::String(v))).collect()))
+        }
+    }
+}
+
+/// Merges the variables of the files into a [`Merge`], reporting on it as the
+/// options say.
+struct Loader<'a> {
+    config: &'a Config,
+    merge: Merge,
+    original_keys: HashMap<String, String>,
+}
+
+impl<'a> Loader<'a> {
+    fn new(config: &'a Config) -> Self {
+        let mut merge = Merge::new(&config.load);
+        merge.no_override = config.no_override;
+        Loader {
+            config,
+            merge,
+            original_keys: HashMap::new(),
+        }
+    }
+
+    /// Adds the variables read from the file at `path`, in key order, with
+    /// their keys converted as the options say and prefixed with `prefix`.
+    fn add_file(&mut self, path: &str, prefix: &str, m: FileMap) {
+        let config = self.config;
+        if !matches!((config.key_case, config.dots_to_underscores), (KeyCase::None, false)) {
+            let mut keys: Vec<&str> = m.keys().map(|k| k.strip_suffix('+').unwrap_or(k)).collect();
+            keys.sort_unstable();
+            for k in keys {
+                let converted = convert_key(config, k);
+                if let Some(previous) = self.original_keys.insert(converted.clone(), k.to_string())
+                {
+                    if previous != k && !config.silent {
+                        warning(&format!(
+                            "{} and {} both become {}; keeping the value of {}",
+                            previous, k, converted, k
+                        ));
+                    }
+                }
+            }
+        }
+        let rename = |k: &str| format!("{}{}", prefix, convert_key(config, k));
+        for (k, change) in self.merge.add_renamed_file(path, m, rename) {
+            match change {
+                Change::Kept(origin) => {
+                    if config.trace {
+                        let msg = format!("{} from {} ignored: already set by {}", k, path, origin);
+                        file_trace(path, &msg);
+                    }
+                }
+                Change::Unset { was_set } => {
+                    if config.trace && was_set {
+                        file_trace(path, &format!("{} unset by {}", k, path));
+                    }
+                }
+                Change::Computed => {}
+                Change::Set { previous, extended } => {
+                    if let Some(problem) = unusable_variable(&k, &self.merge.map[&k]) {
+                        file_error(path, &format!("{}: {}", path, problem));
+                    } else if !is_shell_identifier(&k) && !config.silent {
+                        file_warning(path, &format!(
+                            "{}: {:?} is not a conventional variable name",
+                            path, k
+                        ));
+                    }
+                    match &previous {
+                        Some(origin) if origin != path && !extended && !config.silent => {
+                            file_warning(
+                                path,
+                                &format!("{} redefined by {} (was set by {})", k, path, origin),
+                            );
+                        }
+                        _ => {}
+                    }
+                    self.trace_set(&k, path, previous);
+                }
+            }
+        }
+    }
+
+    /// Sets the variables of the `_compute` tables, filling their templates
+    /// with the final variables, and exits if one can't be. Call it once -e
+    /// and --default have been applied.
+    fn compute(&mut self) {
+        let config = self.config;
+        match self.merge.compute(config.allow_unset, config.inherits()) {
+            Err(EnvfError::File { path, error }) => {
+                file_error(&path, &format!("{}: {}", path, config.describe(&error)))
+            }
+            Err(err) => error_without_usage(&config.describe(&err)),
+            Ok(computed) => {
+                for (k, previous) in computed {
+                    let path = self.merge.origins[&k].clone();
+                    if let Some(problem) = unusable_variable(&k, &self.merge.map[&k]) {
+                        file_error(&path, &format!("{}: {}", path, problem));
+                    }
+                    self.trace_set(&k, &path, previous);
+                }
+            }
+        }
+    }
+
+    /// Drops the variables loaded so far that the current environment already
+    /// sets, for --defaults-only.
+    fn keep_inherited(&mut self) {
+        for key in self.merge.names() {
+            if env::var_os(&key).is_none() {
+                continue;
+            }
+            if let Some(origin) = self.merge.unset(&key) {
+                if self.config.trace {
+                    file_trace(&origin, &format!("{} from {} ignored: already in the environment", key, origin));
+                }
+            }
+        }
+    }
+
+    /// Sets `key` to `value`, recording `origin` as where it comes from.
+    fn set(&mut self, key: String, value: String, origin: &str) {
+        let previous = self.merge.set(key.clone(), value, origin);
+        self.trace_set(&key, origin, previous);
+    }
+
+    /// Reports, for --trace, that `key` was set by `origin`, replacing the
+    /// value set by `previous` if any.
+    fn trace_set(&self, key: &str, origin: &str, previous: Option<String>) {
+        if !self.config.trace {
+            return;
+        }
+        let msg = match previous {
+            None => format!("{} <= {}", key, origin),
+            Some(previous) => format!("{} <= {} (overrides {})", key, origin, previous),
+        };
+        // -e and --default aren't files.
+        if origin.starts_with("--") || origin == "-e" {
+            emit("trace", None, &msg);
+        } else {
+            file_trace(origin, &msg);
+        }
+    }
+}
+
+/// Writes on stdout, as a JSON object, whether `key` is set for the command
+/// and, if so, its value and origin, and the origins of the values it
+/// replaced. Exits with status 1 if it isn't set.
+fn explain(
+    key: &str,
+    value: Option<(String, &str)>,
+    overridden: Option<&Vec<String>>,
+    mask: bool,
+) -> ! {
+    let mut object = serde_json::Map::new();
+    object.insert(String::from("key"), serde_json::Value::from(key));
+    object.insert(String::from("set"), serde_json::Value::from(value.is_some()));
+    if let Some((value, origin)) = &value {
+        let value = if mask { MASK } else { value };
+        object.insert(String::from("value"), serde_json::Value::from(value));
+        object.insert(String::from("origin"), serde_json::Value::from(*origin));
+        let overridden = overridden.cloned().unwrap_or_default();
+        object.insert(String::from("overrides"), serde_json::Value::from(overridden));
+    }
+    println!("{}", serde_json::Value::Object(object));
+    process::exit(if value.is_some() { 0 } else { 1 });
+}
+
+/// Writes `map` to stdout in `format`. Variables are always sorted by name,
+/// since `EnvMap` itself has no order, so that the same inputs give
+/// byte-identical output.
+fn print_env(
+    out: &mut dyn Write,
+    map: &EnvMap,
+    format: PrintFormat,
+    silent: bool,
+) -> io::Result<()> {
+    if let PrintFormat::Json = format {
+        let sorted: BTreeMap<&String, &String> = map.iter().collect();
+        match serde_json::to_string_pretty(&sorted) {
+            Err(err) => error_without_usage(&format!("Couldn't serialize variables: {}", err)),
+            Ok(json) => writeln!(out, "{}", json)?,
+        }
+        return Ok(());
+    }
+    let mut keys: Vec<&String> = map.keys().collect();
+    keys.sort();
+    for key in keys {
+        let value = &map[key];
+        match format {
+            PrintFormat::Lines => {
+                if value.contains('\n') && !silent {
+                    warning(&format!("value of {} contains a newline", key));
+                }
+                writeln!(out, "{}={}", key, value)?;
+            }
+            PrintFormat::Json => unreachable!(),
+            PrintFormat::Export(style) => {
+                if !is_shell_identifier(key) {
+                    if !silent {
+                        warning(&format!("{} skipped: not a valid shell identifier", key));
+                    }
+                    continue;
+                }
+                match style.quote(value) {
+                    Some(quoted) => writeln!(out, "export {}={}", key, quoted)?,
+                    None if !silent => {
+                        warning(&format!("{} skipped: its value needs quoting", key))
+                    }
+                    None => {}
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes on stdout the lines that differ between `expected`, the contents of
+/// the snapshot at `path`, and `actual`, both sorted, prefixed with - and +
+/// after unified diff headers. Returns whether they have the same lines.
+fn diff_env(path: &str, expected: &str, actual: &str) -> bool {
+    let mut expected: Vec<&str> = expected.lines().filter(|line| !line.is_empty()).collect();
+    let mut actual: Vec<&str> = actual.lines().collect();
+    expected.sort_unstable();
+    actual.sort_unstable();
+    if expected == actual {
+        return true;
+    }
+    println!("--- {}", path);
+    println!("+++ resolved environment");
+    let (mut i, mut j) = (0, 0);
+    while i < expected.len() || j < actual.len() {
+        match (expected.get(i), actual.get(j)) {
+            (Some(e), Some(a)) if e == a => {
+                i += 1;
+                j += 1;
+            }
+            (Some(e), Some(a)) if e > a => {
+                println!("+{}", a);
+                j += 1;
+            }
+            (Some(e), _) => {
+                println!("-{}", e);
+                i += 1;
+            }
+            (None, Some(a)) => {
+                println!("+{}", a);
+                j += 1;
+            }
+            (None, None) => unreachable!(),
+        }
+    }
+    false
+}
+
+/// Writes `map` to the file at `path` as print_env does, through a temporary
+/// file renamed into place so that `path` is never left half-written.
+fn write_env(path: &str, map: &EnvMap, format: PrintFormat, silent: bool) -> io::Result<()> {
+    let temp = format!("{}.{}.tmp", path, process::id());
+    let written = fs::File::create(&temp).and_then(|file| {
+        let mut out = io::BufWriter::new(file);
+        print_env(&mut out, map, format, silent)?;
+        out.into_inner().map_err(io::IntoInnerError::into_error)?.sync_all()?;
+        fs::rename(&temp, path)
+    });
+    if written.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    written
+}
+
+/// Writes on stderr the command of `config` and how its environment differs
+/// from the current one: + for added variables, ~ for changed ones and - for
+/// removed ones.
+fn describe_run(config: &Config, map: &EnvMap) {
+    let current: BTreeMap<String, String> = env::vars_os()
+        .map(|(k, v)| (k.to_string_lossy().into_owned(), v.to_string_lossy().into_owned()))
+        .collect();
+    let mut child: BTreeMap<&str, &str> = BTreeMap::new();
+    if config.inherits() {
+        child.extend(current.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+    }
+    child.extend(map.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+    for key in &config.unset {
+        child.remove(key.as_str());
+    }
+    let show = |value: &str| {
+        if config.load.mask {
+            String::from(MASK)
+        } else {
+            String::from(value)
+        }
+    };
+    for command in &config.command {
+        eprintln!("Command: {:?}", command);
+    }
+    if let Some(dir) = &config.chdir {
+        eprintln!("Directory: {}", dir);
+    }
+    let mut keys: Vec<&str> = current.keys().map(String::as_str).collect();
+    keys.extend(child.keys().filter(|k| !current.contains_key(**k)));
+    keys.sort();
+    for key in keys {
+        match (current.get(key), child.get(key)) {
+            (None, Some(value)) => eprintln!("+ {}={}", key, show(value)),
+            (Some(old), Some(value)) if old != value => {
+                eprintln!("~ {}={} (was {})", key, show(value), show(old))
+            }
+            (Some(_), None) => eprintln!("- {}", key),
+            _ => {}
+        }
+    }
+}
+
+/// The length of the longest KEY=VALUE string exec accepts in an environment,
+/// its terminating NUL included (MAX_ARG_STRLEN).
+#[cfg(target_os = "linux")]
+const MAX_VARIABLE_LEN: Option<usize> = Some(32 * 4096);
+#[cfg(not(target_os = "linux"))]
+const MAX_VARIABLE_LEN: Option<usize> = None;
+
+/// Warns about the values of `map` longer than --max-value-len, and aborts if
+/// one is too long for the command to be started at all.
+fn check_value_lengths(config: &Config, map: &EnvMap) {
+    let mut keys: Vec<&String> = map.keys().collect();
+    keys.sort();
+    for key in keys {
+        let len = map[key].len();
+        match MAX_VARIABLE_LEN {
+            Some(max) if key.len() + len + 2 > max => error_without_usage(&format!(
+                "{} is too long to be passed to the command ({} bytes, at most {} with its name)",
+                key,
+                len,
+                max - 2
+            )),
+            _ if config.max_value_len > 0 && len > config.max_value_len && !config.silent => {
+                warning(&format!(
+                    "{} is {} bytes long, more than --max-value-len ({})",
+                    key, len, config.max_value_len
+                ))
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The commands of `config` as a line a POSIX shell would run the same way,
+/// preceded by a cd to its --chdir directory, if any.
+fn command_line(config: &Config) -> String {
+    let word = |arg: &str| {
+        QuoteStyle::None
+            .quote(arg)
+            .or_else(|| QuoteStyle::Single.quote(arg))
+            .unwrap_or_default()
+    };
+    let commands: Vec<String> = config
+        .command
+        .iter()
+        .map(|command| command.iter().map(|arg| word(arg)).collect::<Vec<String>>().join(" "))
+        .collect();
+    match &config.chdir {
+        Some(dir) => format!("cd {} && {}", word(dir), commands.join(" && ")),
+        None => commands.join(" && "),
+    }
+}
+
+fn build_command(
+    config: &Config,
+    argv: &[String],
+    map: &EnvMap,
+    user: Option<&UserIds>,
+) -> process::Command {
+    let mut command = process::Command::new(&argv[0]);
+    if !config.inherits() {
+        command.env_clear();
+    }
+    if let Some(dir) = &config.chdir {
+        command.current_dir(dir);
+    }
+    set_user(&mut command, user);
+    command
+        .args(&argv[1..])
+        .envs(map);
+    for key in &config.unset {
+        command.env_remove(key);
+    }
+    command
+}
+
+/// The user, group and supplementary group IDs --user runs the command as.
+#[derive(Clone, Debug, PartialEq)]
+struct UserIds {
+    uid: u32,
+    gid: u32,
+    groups: Vec<u32>,
+}
+
+/// Resolves the NAME[:GROUP] of --user, where both can also be numeric IDs.
+/// Without GROUP, the primary group of NAME is used, so NAME must have an
+/// entry. The supplementary groups are those of NAME, if it has an entry, and
+/// only GROUP otherwise.
+#[cfg(unix)]
+fn user_ids(spec: &str) -> Result<UserIds, String> {
+    let (user, group) = match spec.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (spec, None),
+    };
+    let entry = match lookup_user(user) {
+        Some(entry) => Some(entry),
+        None => match user.parse() {
+            Ok(uid) => lookup_uid(uid),
+            Err(_) => return Err(format!("--user: unknown user {}", user)),
+        },
+    };
+    let gid = match (group, &entry) {
+        (Some(group), _) => match lookup_group(group).or_else(|| group.parse().ok()) {
+            Some(gid) => gid,
+            None => return Err(format!("--user: unknown group {}", group)),
+        },
+        (None, Some(entry)) => entry.gid,
+        (None, None) => {
+            return Err(format!(
+                "--user: no user has ID {}, so its group must be given as {}:GROUP",
+                user, user
+            ))
+        }
+    };
+    match entry {
+        Some(entry) => Ok(UserIds {
+            uid: entry.uid,
+            gid,
+            groups: group_list(&entry.name, gid),
+        }),
+        None => Ok(UserIds {
+            uid: user.parse().expect("a numeric ID"),
+            gid,
+            groups: vec![gid],
+        }),
+    }
+}
+
+#[cfg(not(unix))]
+fn user_ids(_spec: &str) -> Result<UserIds, String> {
+    Err("--user is only supported on unix".to_string())
+}
+
+/// What the user database says about a user.
+#[cfg(unix)]
+struct UserEntry {
+    uid: u32,
+    gid: u32,
+    name: CString,
+}
+
+/// The entry of the user called `name`.
+#[cfg(unix)]
+fn lookup_user(name: &str) -> Option<UserEntry> {
+    let name = CString::new(name).ok()?;
+    // Safety: name is NUL-terminated, and the entry is copied out before
+    // anything else can overwrite it.
+    unsafe { user_entry(libc::getpwnam(name.as_ptr())) }
+}
+
+/// The entry of the user whose ID is `uid`.
+#[cfg(unix)]
+fn lookup_uid(uid: u32) -> Option<UserEntry> {
+    // Safety: as in lookup_user.
+    unsafe { user_entry(libc::getpwuid(uid)) }
+}
+
+/// Copies out the entry returned by getpwnam or getpwuid.
+///
+/// # Safety
+///
+/// `entry` must be null or point to a valid entry.
+#[cfg(unix)]
+unsafe fn user_entry(entry: *const libc::passwd) -> Option<UserEntry> {
+    if entry.is_null() || (*entry).pw_name.is_null() {
+        None
+    } else {
+        Some(UserEntry {
+            uid: (*entry).pw_uid,
+            gid: (*entry).pw_gid,
+            name: CStr::from_ptr((*entry).pw_name).to_owned(),
+        })
+    }
+}
+
+/// The type getgrouplist lists groups as.
+#[cfg(all(unix, any(target_os = "macos", target_os = "ios")))]
+type ListedGroup = libc::c_int;
+#[cfg(all(unix, not(any(target_os = "macos", target_os = "ios"))))]
+type ListedGroup = libc::gid_t;
+
+/// The groups of the user called `name`, whose primary group is taken to be
+/// `gid`, as initgroups would set them.
+#[cfg(unix)]
+fn group_list(name: &CStr, gid: u32) -> Vec<u32> {
+    let mut groups: Vec<ListedGroup> = vec![0; 64];
+    loop {
+        let mut count = groups.len() as libc::c_int;
+        // Safety: groups has room for count groups, and getgrouplist sets
+        // count to how many it needs when that isn't enough.
+        let found = unsafe {
+            libc::getgrouplist(name.as_ptr(), gid as _, groups.as_mut_ptr(), &mut count)
+        };
+        if found >= 0 {
+            groups.truncate(count as usize);
+            return groups.into_iter().map(|group| group as _).collect();
+        }
+        let needed = (count as usize).max(groups.len() * 2);
+        groups.resize(needed, 0);
+    }
+}
+
+/// The ID of the group called `name`.
+#[cfg(unix)]
+fn lookup_group(name: &str) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+    // Safety: as in lookup_user.
+    unsafe {
+        let entry = libc::getgrnam(name.as_ptr());
+        if entry.is_null() {
+            None
+        } else {
+            Some((*entry).gr_gid)
+        }
+    }
+}
+
+/// Makes `command` run as `user`, with only its supplementary groups. They
+/// are set in the child, before the group and user IDs, since the standard
+/// library would only drop them.
+#[cfg(unix)]
+fn set_user(command: &mut process::Command, user: Option<&UserIds>) {
+    let UserIds { uid, gid, groups } = match user {
+        None => return,
+        Some(user) => user.clone(),
+    };
+    let groups: Vec<libc::gid_t> = groups.into_iter().map(|group| group as _).collect();
+    // Safety: setgroups, setgid and setuid are async-signal-safe, and groups
+    // is allocated before the fork.
+    unsafe {
+        command.pre_exec(move || {
+            if libc::geteuid() == 0 && libc::setgroups(groups.len() as _, groups.as_ptr()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::setgid(gid) != 0 || libc::setuid(uid) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(not(unix))]
+fn set_user(_command: &mut process::Command, _user: Option<&UserIds>) {}
+
+/// Where the command is looked for when its PATH is unset, as with execvp.
+#[cfg(unix)]
+const DEFAULT_PATH: &str = "/bin:/usr/bin";
+
+/// Aborts with a clearer message than the exec error if the command of
+/// `config` has no `/` and isn't in the PATH it will be run with.
+#[cfg(unix)]
+fn check_command_path(config: &Config, argv: &[String], map: &EnvMap) {
+    let name = &argv[0];
+    if name.contains('/') {
+        return;
+    }
+    let path = if config.unset.iter().any(|key| key == "PATH") {
+        None
+    } else {
+        match map.get("PATH") {
+            Some(path) => Some(OsString::from(path)),
+            None if config.inherits() => env::var_os("PATH"),
+            None => None,
+        }
+    };
+    let dirs = path.clone().unwrap_or_else(|| OsString::from(DEFAULT_PATH));
+    let found = env::split_paths(&dirs).any(|dir| {
+        let dir = match &config.chdir {
+            Some(chdir) => Path::new(chdir).join(dir),
+            None => dir,
+        };
+        match dir.join(name).metadata() {
+            Ok(meta) => meta.is_file() && meta.permissions().mode() & 0o111 != 0,
+            Err(_) => false,
+        }
+    });
+    if found {
+        return;
+    }
+    match path {
+        Some(path) => error_without_usage(&format!(
+            "Command {} not found in PATH ({})",
+            name,
+            path.to_string_lossy()
+        )),
+        None => error_without_usage(&format!(
+            "Command {} not found: PATH isn't set for it, so only {} was searched",
+            name, DEFAULT_PATH
+        )),
+    }
+}
+
+/// Looking commands up is left to the system, which may add extensions.
+#[cfg(not(unix))]
+fn check_command_path(_config: &Config, _argv: &[String], _map: &EnvMap) {}
+
+/// Runs the commands of `config` in turn with the variables of `map`, exiting
+/// with the status of the last one or of the first that fails; only returns,
+/// with the command concerned, if one couldn't be started.
+fn run_command<'a>(
+    config: &'a Config,
+    map: &EnvMap,
+    user: Option<&UserIds>,
+) -> (&'a [String], io::Error) {
+    let (last, first) = config.command.split_last().expect("a command to run");
+    for argv in first {
+        match spawn_and_wait(build_command(config, argv, map, user), config.timeout) {
+            Err(err) => return (argv, err),
+            Ok(0) => {}
+            Ok(code) => process::exit(code),
+        }
+    }
+    (last, run_last_command(config, build_command(config, last, map, user)))
+}
+
+fn run_last_command(config: &Config, command: process::Command) -> io::Error {
+    if config.spawn || config.timeout.is_some() {
+        spawn_and_exit(command, config.timeout)
+    } else {
+        exec_command(command)
+    }
+}
+
+#[cfg(unix)]
+fn exec_command(mut command: process::Command) -> io::Error {
+    command.exec()
+}
+
+/// Without exec, the closest we can get is waiting for the command.
+#[cfg(not(unix))]
+fn exec_command(command: process::Command) -> io::Error {
+    spawn_and_exit(command, None)
+}
+
+fn spawn_and_exit(command: process::Command, timeout: Option<Duration>) -> io::Error {
+    match spawn_and_wait(command, timeout) {
+        Ok(code) => process::exit(code),
+        Err(err) => err,
+    }
+}
+
+/// The exit status when the command is stopped by --timeout, as with timeout(1).
+const TIMEOUT_EXIT_CODE: i32 = 124;
+
+/// How long the command has to exit once asked to by --timeout.
+#[cfg(unix)]
+const TIMEOUT_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// Runs `command` as a child of envf and returns the exit code envf should
+/// exit with (128 + N if the child was killed by signal N, like shells do).
+/// The child is stopped if it runs for longer than `timeout`.
+#[cfg_attr(not(target_os = "linux"), allow(unused_mut))]
+fn spawn_and_wait(mut command: process::Command, timeout: Option<Duration>) -> io::Result<i32> {
+    #[cfg(target_os = "linux")]
+    unsafe {
+        command.pre_exec(|| {
+            if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut child = command.spawn()?;
+    forward_signals(&child);
+    match timeout {
+        None => Ok(exit_code(child.wait()?)),
+        Some(timeout) => match wait_until(&mut child, Instant::now() + timeout)? {
+            Some(status) => Ok(exit_code(status)),
+            None => {
+                stop(&mut child)?;
+                Ok(TIMEOUT_EXIT_CODE)
+            }
+        },
+    }
+}
+
+/// The child that forward_signal passes signals on to, once spawned.
+#[cfg(unix)]
+static CHILD_PID: AtomicI32 = AtomicI32::new(0);
+
+/// Passes the signals asking envf to stop on to `child` from now on, so that it
+/// can shut down by itself and envf exit with its status.
+#[cfg(unix)]
+fn forward_signals(child: &process::Child) {
+    CHILD_PID.store(child.id() as i32, Ordering::SeqCst);
+    for &signal in &[libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+        // Safety: forward_signal only calls kill, which is async-signal-safe.
+        unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = forward_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(signal, &action, ptr::null_mut());
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn forward_signals(_child: &process::Child) {}
+
+#[cfg(unix)]
+extern "C" fn forward_signal(signal: libc::c_int) {
+    let pid = CHILD_PID.load(Ordering::SeqCst);
+    if pid > 0 {
+        unsafe {
+            libc::kill(pid, signal);
+        }
+    }
+}
+
+/// Waits for `child` to exit, giving up at `deadline`.
+fn wait_until(
+    child: &mut process::Child,
+    deadline: Instant,
+) -> io::Result<Option<process::ExitStatus>> {
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(None);
+        }
+        thread::sleep((deadline - now).min(Duration::from_millis(10)));
+    }
+}
+
+/// Sends SIGTERM to `child`, then SIGKILL if it is still running after the
+/// grace period.
+#[cfg(unix)]
+fn stop(child: &mut process::Child) -> io::Result<()> {
+    if unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if wait_until(child, Instant::now() + TIMEOUT_GRACE_PERIOD)?.is_none() {
+        child.kill()?;
+        child.wait()?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn stop(child: &mut process::Child) -> io::Result<()> {
+    child.kill()?;
+    child.wait().map(|_| ())
+}
+
+#[cfg(unix)]
+fn exit_code(status: process::ExitStatus) -> i32 {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => 1,
+    }
+}
+
+#[cfg(not(unix))]
+fn exit_code(status: process::ExitStatus) -> i32 {
+    status.code().unwrap_or(1)
+}
+
+enum ArgParseResult {
+    Config(Box<Config>),
+    Err(&'static str),
+    /// With --strict-args, an argument looking like an option isn't one.
+    UnknownOption(String),
+    Help,
+}
+
+/// Separates the options read from the --config file, which are defaults,
+/// from those of the --project file, which override them, and those from
+/// those of the command line, which override both. No actual argument can be
+/// mistaken for it, since it contains a NUL byte.
+const DEFAULTS_END: &str = "\0defaults-end";
+
+/// The flags of which the defaults are dropped when contradicted by the options
+/// overriding them, rather than rejected along with them, as -s is by --strict.
+#[derive(Clone, Copy, Default)]
+struct ConflictingFlags {
+    silent: bool,
+    strict: bool,
+    keep_going: bool,
+    no_inherit: bool,
+}
+
+impl ConflictingFlags {
+    /// Combines these defaults with the flags of the options overriding them,
+    /// which add `sources`: the defaults contradicting those are dropped.
+    fn overridden_by(self, flags: ConflictingFlags, sources: &[(Source, String)]) -> Self {
+        let from_env = sources.iter().any(|(s, _)| matches!(s, Source::EnvVar(_)));
+        ConflictingFlags {
+            silent: flags.silent || (self.silent && !flags.strict),
+            strict: flags.strict || (self.strict && !flags.silent && !flags.keep_going),
+            keep_going: flags.keep_going || (self.keep_going && !flags.strict),
+            no_inherit: flags.no_inherit || (self.no_inherit && !from_env),
+        }
+    }
+}
+
+fn parse_args(args: impl Iterator<Item = String>) -> ArgParseResult {
+    let mut sources: Vec<(Source, String)> = vec![];
+    let mut environment: Option<String> = None;
+    let mut layer: Option<String> = None;
+    let mut file_format: Option<FileFormat> = None;
+    let mut silent = false;
+    let mut strict = false;
+    let mut fail_on_skip = false;
+    let mut on_missing_file = MissingFilePolicy::Warn;
+    let mut keep_going = false;
+    let mut require_nonempty = false;
+    let mut dump_config = false;
+    let mut validate = false;
+    let mut parse_only = false;
+    let mut allow_empty_command = false;
+    let mut dry_run = false;
+    let mut echo_command = false;
+    let mut explain: Option<String> = None;
+    let mut rc_file: Option<String> = None;
+    let mut project: Option<String> = None;
+    let mut summary = false;
+    let mut strict_args = false;
+    let mut allow_unset = false;
+    let mut print: Option<PrintFormat> = None;
+    let mut output: Option<String> = None;
+    let mut diff: Option<String> = None;
+    let mut format: Option<PrintFormat> = None;
+    let mut quote_style: Option<QuoteStyle> = None;
+    let mut color = ColorMode::Auto;
+    let mut trace = false;
+    let mut log_json = false;
+    let mut chdir: Option<String> = None;
+    let mut expand_tilde = true;
+    let mut defaults_only = false;
+    let mut no_override = false;
+    let mut max_value_len = 65536;
+    let mut user: Option<String> = None;
+    let mut spawn = false;
+    let mut chain = false;
+    let mut timeout: Option<Duration> = None;
+    let mut retry = 0;
+    let mut retry_delay = Duration::from_millis(500);
+    let mut overrides: Vec<(String, String)> = vec![];
+    let mut defaults: Vec<(String, String)> = vec![];
+    let mut renames: Vec<(String, String)> = vec![];
+    let mut map_files: Vec<String> = vec![];
+    let mut unset: Vec<String> = vec![];
+    let mut append_path: Vec<String> = vec![];
+    let mut only: Vec<String> = vec![];
+    let mut exclude: Vec<String> = vec![];
+    let mut require: Vec<String> = vec![];
+    let mut clear = false;
+    let mut no_inherit = false;
+    let mut prefix = String::new();
+    let mut prefix_from = None;
+    let mut key_case = KeyCase::None;
+    let mut dots_to_underscores = false;
+    let mut load = LoadOptions::default();
+    let mut default_flags = ConflictingFlags::default();
+    let mut default_format: Option<PrintFormat> = None;
+    let mut level_start = 0;
+    let mut args = args.peekable();
+    loop {
+        match args.peek() {
+            None => break,
+            Some(arg) => {
+                if arg == "--" {
+                    args.next();
+                    break;
+                } else if arg == DEFAULTS_END {
+                    let flags = ConflictingFlags {
+                        silent,
+                        strict,
+                        keep_going,
+                        no_inherit,
+                    };
+                    default_flags = default_flags.overridden_by(flags, &sources[level_start..]);
+                    (silent, strict, keep_going, no_inherit) = (false, false, false, false);
+                    default_format = format.take().or(default_format);
+                    level_start = sources.len();
+                } else if arg == "-h" || arg == "--help" {
+                    return ArgParseResult::Help;
+                } else if arg == "-s" {
+                    silent = true;
+                } else if arg == "--strict" {
+                    strict = true;
+                } else if arg == "--mask" {
+                    load.mask = true;
+                } else if arg == "--fail-on-skip" {
+                    fail_on_skip = true;
+                } else if arg == "--on-missing-file" {
+                    args.next();
+                    match args.peek().map(|p| parse_missing_file_policy(p)) {
+                        None => return ArgParseResult::Err("Trailing --on-missing-file"),
+                        Some(None) => return ArgParseResult::Err(MISSING_FILE_POLICY_ERROR),
+                        Some(Some(policy)) => on_missing_file = policy,
+                    }
+                } else if let Some(policy) = arg.strip_prefix("--on-missing-file=") {
+                    match parse_missing_file_policy(policy) {
+                        None => return ArgParseResult::Err(MISSING_FILE_POLICY_ERROR),
+                        Some(policy) => on_missing_file = policy,
+                    }
+                } else if arg == "--keep-going" {
+                    keep_going = true;
+                } else if arg == "--summary" {
+                    summary = true;
+                } else if arg == "--config" {
+                    args.next();
+                    match args.peek() {
+                        None => return ArgParseResult::Err("Trailing --config"),
+                        Some(path) => rc_file = Some(path.to_string()),
+                    }
+                } else if let Some(path) = arg.strip_prefix("--config=") {
+                    rc_file = Some(path.to_string());
+                } else if arg == "--project" {
+                    args.next();
+                    match args.peek() {
+                        None => return ArgParseResult::Err("Trailing --project"),
+                        Some(path) => project = Some(path.to_string()),
+                    }
+                } else if let Some(path) = arg.strip_prefix("--project=") {
+                    project = Some(path.to_string());
+                } else if arg == "--dry-run" {
+                    dry_run = true;
+                } else if arg == "--echo-command" {
+                    echo_command = true;
+                } else if arg == "--explain" {
+                    args.next();
+                    match args.peek() {
+                        None => return ArgParseResult::Err("Trailing --explain"),
+                        Some(key) => explain = Some(key.to_string()),
+                    }
+                } else if let Some(key) = arg.strip_prefix("--explain=") {
+                    explain = Some(key.to_string());
+                } else if arg == "--validate" {
+                    validate = true;
+                } else if arg == "--parse-only" {
+                    parse_only = true;
+                    load.parse_only = true;
+                } else if arg == "--allow-empty-command" {
+                    allow_empty_command = true;
+                } else if arg == "--dump-config" {
+                    dump_config = true;
+                } else if arg == "--require-nonempty" {
+                    require_nonempty = true;
+                } else if arg == "--allow-unset" {
+                    allow_unset = true;
+                } else if arg == "-o" {
+                    args.next();
+                    match args.peek() {
+                        None => return ArgParseResult::Err("Trailing -o"),
+                        Some(path) => output = Some(path.to_string()),
+                    }
+                } else if let Some(path) = arg.strip_prefix("-o=") {
+                    output = Some(path.to_string());
+                } else if arg == "--diff" {
+                    args.next();
+                    match args.peek() {
+                        None => return ArgParseResult::Err("Trailing --diff"),
+                        Some(path) => diff = Some(path.to_string()),
+                    }
+                } else if let Some(path) = arg.strip_prefix("--diff=") {
+                    diff = Some(path.to_string());
+                } else if arg == "--print" {
+                    print = print.or(Some(PrintFormat::Lines));
+                } else if arg == "--format" {
+                    args.next();
+                    match args.peek().map(|f| parse_print_format(f)) {
+                        None => return ArgParseResult::Err("Trailing --format"),
+                        Some(None) => return ArgParseResult::Err("--format expects plain or json"),
+                        Some(f) => format = f,
+                    }
+                } else if let Some(f) = arg.strip_prefix("--format=") {
+                    match parse_print_format(f) {
+                        None => return ArgParseResult::Err("--format expects plain or json"),
+                        f => format = f,
+                    }
+                } else if arg == "--export" {
+                    print = Some(PrintFormat::Export(QuoteStyle::Single));
+                } else if arg == "--quote-style" {
+                    args.next();
+                    match args.peek().map(|q| parse_quote_style(q)) {
+                        None => return ArgParseResult::Err("Trailing --quote-style"),
+                        Some(None) => return ArgParseResult::Err(QUOTE_STYLE_ERROR),
+                        Some(q) => quote_style = q,
+                    }
+                } else if let Some(q) = arg.strip_prefix("--quote-style=") {
+                    match parse_quote_style(q) {
+                        None => return ArgParseResult::Err(QUOTE_STYLE_ERROR),
+                        q => quote_style = q,
+                    }
+                } else if arg == "--color" {
+                    args.next();
+                    match args.peek().map(|c| parse_color_mode(c)) {
+                        None => return ArgParseResult::Err("Trailing --color"),
+                        Some(None) => return ArgParseResult::Err(COLOR_MODE_ERROR),
+                        Some(Some(mode)) => color = mode,
+                    }
+                } else if let Some(mode) = arg.strip_prefix("--color=") {
+                    match parse_color_mode(mode) {
+                        None => return ArgParseResult::Err(COLOR_MODE_ERROR),
+                        Some(mode) => color = mode,
+                    }
+                } else if arg == "--trace" {
+                    trace = true;
+                } else if arg == "--log-json" {
+                    log_json = true;
+                } else if arg == "--chdir" {
+                    args.next();
+                    match args.peek() {
+                        None => return ArgParseResult::Err("Trailing --chdir"),
+                        Some(dir) => chdir = Some(dir.to_string()),
+                    }
+                } else if let Some(dir) = arg.strip_prefix("--chdir=") {
+                    chdir = Some(dir.to_string());
+                } else if arg == "--user" {
+                    args.next();
+                    match args.peek() {
+                        None => return ArgParseResult::Err("Trailing --user"),
+                        Some(spec) => user = Some(spec.to_string()),
+                    }
+                } else if let Some(spec) = arg.strip_prefix("--user=") {
+                    user = Some(spec.to_string());
+                } else if arg == "--spawn" {
+                    spawn = true;
+                } else if arg == "--chain" {
+                    chain = true;
+                } else if arg == "--retry" {
+                    args.next();
+                    match args.peek().map(|n| n.parse::<u32>()) {
+                        None => return ArgParseResult::Err("Trailing --retry"),
+                        Some(Err(_)) => return ArgParseResult::Err(RETRY_ERROR),
+                        Some(Ok(n)) => retry = n,
+                    }
+                } else if let Some(n) = arg.strip_prefix("--retry=") {
+                    match n.parse::<u32>() {
+                        Err(_) => return ArgParseResult::Err(RETRY_ERROR),
+                        Ok(n) => retry = n,
+                    }
+                } else if arg == "--retry-delay" {
+                    args.next();
+                    match args.peek().map(|ms| ms.parse::<u64>()) {
+                        None => return ArgParseResult::Err("Trailing --retry-delay"),
+                        Some(Err(_)) => return ArgParseResult::Err(RETRY_DELAY_ERROR),
+                        Some(Ok(ms)) => retry_delay = Duration::from_millis(ms),
+                    }
+                } else if let Some(ms) = arg.strip_prefix("--retry-delay=") {
+                    match ms.parse::<u64>() {
+                        Err(_) => return ArgParseResult::Err(RETRY_DELAY_ERROR),
+                        Ok(ms) => retry_delay = Duration::from_millis(ms),
+                    }
+                } else if arg == "--timeout" {
+                    args.next();
+                    match args.peek().map(|t| parse_timeout(t)) {
+                        None => return ArgParseResult::Err("Trailing --timeout"),
+                        Some(None) => return ArgParseResult::Err(TIMEOUT_ERROR),
+                        Some(Some(t)) => timeout = t,
+                    }
+                } else if let Some(t) = arg.strip_prefix("--timeout=") {
+                    match parse_timeout(t) {
+                        None => return ArgParseResult::Err(TIMEOUT_ERROR),
+                        Some(t) => timeout = t,
+                    }
+                } else if arg == "--clear" {
+                    clear = true;
+                } else if arg == "--no-inherit" {
+                    no_inherit = true;
+                } else if arg == "--upper" {
+                    key_case = KeyCase::Upper;
+                } else if arg == "--dots-to-underscores" {
+                    dots_to_underscores = true;
+                } else if arg == "--key-case" {
+                    args.next();
+                    match args.peek().map(|c| parse_key_case(c)) {
+                        None => return ArgParseResult::Err("Trailing --key-case"),
+                        Some(None) => return ArgParseResult::Err(KEY_CASE_ERROR),
+                        Some(Some(case)) => key_case = case,
+                    }
+                } else if let Some(case) = arg.strip_prefix("--key-case=") {
+                    match parse_key_case(case) {
+                        None => return ArgParseResult::Err(KEY_CASE_ERROR),
+                        Some(case) => key_case = case,
+                    }
+                } else if arg == "-f" {
+                    args.next();
+                    match args.peek() {
+                        None => return ArgParseResult::Err("Trailing -f"),
+                        Some(path) => sources.push((file_source(path, file_format), prefix.clone())),
+                    }
+                } else if let Some(path) = arg.strip_prefix("-f=") {
+                    sources.push((file_source(path, file_format), prefix.clone()));
+                } else if arg == "--file-list" {
+                    args.next();
+                    match args.peek() {
+                        None => return ArgParseResult::Err("Trailing --file-list"),
+                        Some(path) => sources.push(file_list_source(path, file_format, &prefix)),
+                    }
+                } else if let Some(path) = arg.strip_prefix("--file-list=") {
+                    sources.push(file_list_source(path, file_format, &prefix));
+                } else if arg == "--env-file-format" {
+                    args.next();
+                    match args.peek().map(|f| parse_file_format(f)) {
+                        None => return ArgParseResult::Err("Trailing --env-file-format"),
+                        Some(None) => return ArgParseResult::Err(FILE_FORMAT_ERROR),
+                        Some(Some(format)) => file_format = format,
+                    }
+                } else if let Some(format) = arg.strip_prefix("--env-file-format=") {
+                    match parse_file_format(format) {
+                        None => return ArgParseResult::Err(FILE_FORMAT_ERROR),
+                        Some(format) => file_format = format,
+                    }
+                } else if arg == "--env" {
+                    args.next();
+                    match args.peek() {
+                        None => return ArgParseResult::Err("Trailing --env"),
+                        Some(name) => environment = Some(name.to_string()),
+                    }
+                } else if let Some(name) = arg.strip_prefix("--env=") {
+                    environment = Some(name.to_string());
+                } else if arg == "--layer" {
+                    args.next();
+                    match args.peek() {
+                        None => return ArgParseResult::Err("Trailing --layer"),
+                        Some(name) => layer = Some(name.to_string()),
+                    }
+                } else if let Some(name) = arg.strip_prefix("--layer=") {
+                    layer = Some(name.to_string());
+                } else if arg == "--dotenv" {
+                    args.next();
+                    match args.peek() {
+                        None => return ArgParseResult::Err("Trailing --dotenv"),
+                        Some(path) => sources.push((Source::Dotenv(path.to_string()), prefix.clone())),
+                    }
+                } else if let Some(path) = arg.strip_prefix("--dotenv=") {
+                    sources.push((Source::Dotenv(path.to_string()), prefix.clone()));
+                } else if arg == "--from-env" {
+                    args.next();
+                    match args.peek() {
+                        None => return ArgParseResult::Err("Trailing --from-env"),
+                        Some(name) => sources.push((Source::EnvVar(name.to_string()), prefix.clone())),
+                    }
+                } else if let Some(name) = arg.strip_prefix("--from-env=") {
+                    sources.push((Source::EnvVar(name.to_string()), prefix.clone()));
+                } else if arg == "--url" {
+                    args.next();
+                    match args.peek() {
+                        None => return ArgParseResult::Err("Trailing --url"),
+                        Some(url) => sources.push((Source::Url(url.to_string()), prefix.clone())),
+                    }
+                } else if let Some(url) = arg.strip_prefix("--url=") {
+                    sources.push((Source::Url(url.to_string()), prefix.clone()));
+                } else if arg == "-d" {
+                    args.next();
+                    match args.peek() {
+                        None => return ArgParseResult::Err("Trailing -d"),
+                        Some(dir) => sources.push((Source::Dir(dir.to_string()), prefix.clone())),
+                    }
+                } else if let Some(dir) = arg.strip_prefix("-d=") {
+                    sources.push((Source::Dir(dir.to_string()), prefix.clone()));
+                } else if arg == "-e" {
+                    args.next();
+                    match args.peek().map(|a| parse_assignment(a)) {
+                        None => return ArgParseResult::Err("Trailing -e"),
+                        Some(None) => return ArgParseResult::Err("-e expects KEY=VALUE"),
+                        Some(Some(kv)) => overrides.push(kv),
+                    }
+                } else if let Some(assignment) = arg.strip_prefix("-e=") {
+                    match parse_assignment(assignment) {
+                        None => return ArgParseResult::Err("-e expects KEY=VALUE"),
+                        Some(kv) => overrides.push(kv),
+                    }
+                } else if arg == "--default" {
+                    args.next();
+                    match args.peek().map(|a| parse_assignment(a)) {
+                        None => return ArgParseResult::Err("Trailing --default"),
+                        Some(None) => return ArgParseResult::Err("--default expects KEY=VALUE"),
+                        Some(Some(kv)) => defaults.push(kv),
+                    }
+                } else if let Some(assignment) = arg.strip_prefix("--default=") {
+                    match parse_assignment(assignment) {
+                        None => return ArgParseResult::Err("--default expects KEY=VALUE"),
+                        Some(kv) => defaults.push(kv),
+                    }
+                } else if arg == "--rename" {
+                    args.next();
+                    match args.peek().map(|a| parse_rename(a)) {
+                        None => return ArgParseResult::Err("Trailing --rename"),
+                        Some(None) => return ArgParseResult::Err(RENAME_ERROR),
+                        Some(Some(kv)) => renames.push(kv),
+                    }
+                } else if let Some(assignment) = arg.strip_prefix("--rename=") {
+                    match parse_rename(assignment) {
+                        None => return ArgParseResult::Err(RENAME_ERROR),
+                        Some(kv) => renames.push(kv),
+                    }
+                } else if arg == "--map-file" {
+                    args.next();
+                    match args.peek() {
+                        None => return ArgParseResult::Err("Trailing --map-file"),
+                        Some(path) => map_files.push(path.to_string()),
+                    }
+                } else if let Some(path) = arg.strip_prefix("--map-file=") {
+                    map_files.push(path.to_string());
+                } else if arg == "--unset" {
+                    args.next();
+                    match args.peek() {
+                        None => return ArgParseResult::Err("Trailing --unset"),
+                        Some(key) => unset.push(key.to_string()),
+                    }
+                } else if let Some(key) = arg.strip_prefix("--unset=") {
+                    unset.push(key.to_string());
+                } else if arg == "--append-path" {
+                    args.next();
+                    match args.peek() {
+                        None => return ArgParseResult::Err("Trailing --append-path"),
+                        Some(key) => append_path.push(key.to_string()),
+                    }
+                } else if let Some(key) = arg.strip_prefix("--append-path=") {
+                    append_path.push(key.to_string());
+                } else if arg == "--only" {
+                    args.next();
+                    match args.peek() {
+                        None => return ArgParseResult::Err("Trailing --only"),
+                        Some(p) => only.push(p.to_string()),
+                    }
+                } else if let Some(p) = arg.strip_prefix("--only=") {
+                    only.push(p.to_string());
+                } else if arg == "--exclude" {
+                    args.next();
+                    match args.peek() {
+                        None => return ArgParseResult::Err("Trailing --exclude"),
+                        Some(key) => exclude.push(key.to_string()),
+                    }
+                } else if let Some(key) = arg.strip_prefix("--exclude=") {
+                    exclude.push(key.to_string());
+                } else if arg == "--require" {
+                    args.next();
+                    match args.peek() {
+                        None => return ArgParseResult::Err("Trailing --require"),
+                        Some(key) => require.push(key.to_string()),
+                    }
+                } else if let Some(key) = arg.strip_prefix("--require=") {
+                    require.push(key.to_string());
+                } else if arg == "--prefix" {
+                    args.next();
+                    match args.peek() {
+                        None => return ArgParseResult::Err("Trailing --prefix"),
+                        Some(p) => {
+                            prefix = p.to_string();
+                            prefix_from = Some(sources.len());
+                        }
+                    }
+                } else if let Some(p) = arg.strip_prefix("--prefix=") {
+                    prefix = p.to_string();
+                    prefix_from = Some(sources.len());
+                } else if arg == "--section" {
+                    args.next();
+                    match args.peek() {
+                        None => return ArgParseResult::Err("Trailing --section"),
+                        Some(name) => load.section = Some(name.to_string()),
+                    }
+                } else if let Some(name) = arg.strip_prefix("--section=") {
+                    load.section = Some(name.to_string());
+                } else if arg == "--max-value-len" {
+                    args.next();
+                    match args.peek().map(|n| n.parse::<usize>()) {
+                        None => return ArgParseResult::Err("Trailing --max-value-len"),
+                        Some(Err(_)) => return ArgParseResult::Err(MAX_VALUE_LEN_ERROR),
+                        Some(Ok(n)) => max_value_len = n,
+                    }
+                } else if let Some(n) = arg.strip_prefix("--max-value-len=") {
+                    match n.parse::<usize>() {
+                        Err(_) => return ArgParseResult::Err(MAX_VALUE_LEN_ERROR),
+                        Ok(n) => max_value_len = n,
+                    }
+                } else if arg == "--float-format" {
+                    args.next();
+                    match args.peek().map(|p| p.parse::<usize>()) {
+                        None => return ArgParseResult::Err("Trailing --float-format"),
+                        Some(Err(_)) => {
+                            return ArgParseResult::Err("--float-format expects a number of decimals")
+                        }
+                        Some(Ok(precision)) => load.float_precision = Some(precision),
+                    }
+                } else if let Some(precision) = arg.strip_prefix("--float-format=") {
+                    match precision.parse::<usize>() {
+                        Err(_) => {
+                            return ArgParseResult::Err("--float-format expects a number of decimals")
+                        }
+                        Ok(precision) => load.float_precision = Some(precision),
+                    }
+                } else if arg == "--bool-style" {
+                    args.next();
+                    match args.peek().map(|style| parse_bool_style(style)) {
+                        None => return ArgParseResult::Err("Trailing --bool-style"),
+                        Some(None) => return ArgParseResult::Err(BOOL_STYLE_ERROR),
+                        Some(Some(style)) => load.bool_style = style,
+                    }
+                } else if let Some(style) = arg.strip_prefix("--bool-style=") {
+                    match parse_bool_style(style) {
+                        None => return ArgParseResult::Err(BOOL_STYLE_ERROR),
+                        Some(style) => load.bool_style = style,
+                    }
+                } else if arg == "--datetime-format" {
+                    args.next();
+                    match args.peek().map(|f| parse_datetime_format(f)) {
+                        None => return ArgParseResult::Err("Trailing --datetime-format"),
+                        Some(None) => return ArgParseResult::Err(DATETIME_FORMAT_ERROR),
+                        Some(Some(format)) => load.datetime_format = format,
+                    }
+                } else if let Some(format) = arg.strip_prefix("--datetime-format=") {
+                    match parse_datetime_format(format) {
+                        None => return ArgParseResult::Err(DATETIME_FORMAT_ERROR),
+                        Some(format) => load.datetime_format = format,
+                    }
+                } else if arg == "--int-radix" {
+                    args.next();
+                    match args.peek().map(|r| parse_int_radix(r)) {
+                        None => return ArgParseResult::Err("Trailing --int-radix"),
+                        Some(None) => return ArgParseResult::Err(INT_RADIX_ERROR),
+                        Some(Some(radix)) => load.int_radix = radix,
+                    }
+                } else if let Some(radix) = arg.strip_prefix("--int-radix=") {
+                    match parse_int_radix(radix) {
+                        None => return ArgParseResult::Err(INT_RADIX_ERROR),
+                        Some(radix) => load.int_radix = radix,
+                    }
+                } else if arg == "--radix-prefix" {
+                    load.radix_prefix = true;
+                } else if arg == "--int-u64" {
+                    load.int_u64 = true;
+                } else if arg == "--merge-arrays" {
+                    load.mark_arrays = true;
+                } else if arg == "--skip-nonscalar" {
+                    load.skip_nonscalar = true;
+                } else if arg == "--no-override" {
+                    no_override = true;
+                } else if arg == "--defaults-only" {
+                    defaults_only = true;
+                } else if arg == "--expand-tilde" {
+                    expand_tilde = true;
+                } else if arg == "--no-expand-tilde" {
+                    expand_tilde = false;
+                } else if arg == "--null-as-unset" {
+                    load.null_as_unset = true;
+                } else if arg == "--multi-doc" {
+                    load.multi_doc = true;
+                } else if arg == "--no-skip-underscore" {
+                    load.skip_underscore = false;
+                } else if arg == "--separator" {
+                    args.next();
+                    match args.peek() {
+                        None => return ArgParseResult::Err("Trailing --separator"),
+                        Some(sep) => load.separator = sep.to_string(),
+                    }
+                } else if let Some(sep) = arg.strip_prefix("--separator=") {
+                    load.separator = sep.to_string();
+                } else if arg == "--array-separator" {
+                    args.next();
+                    match args.peek() {
+                        None => return ArgParseResult::Err("Trailing --array-separator"),
+                        Some(sep) => load.array_separator = sep.to_string(),
+                    }
+                } else if let Some(sep) = arg.strip_prefix("--array-separator=") {
+                    load.array_separator = sep.to_string();
+                } else if arg == "--strict-args" {
+                    strict_args = true;
+                } else if strict_args && arg.starts_with('-') && arg != "-" {
+                    return ArgParseResult::UnknownOption(arg.to_string());
+                } else {
+                    break;
+                }
+            }
+        }
+        args.next();
+    }
+    let flags = ConflictingFlags {
+        silent,
+        strict,
+        keep_going,
+        no_inherit,
+    };
+    let flags = default_flags.overridden_by(flags, &sources[level_start..]);
+    (silent, strict, keep_going, no_inherit) =
+        (flags.silent, flags.strict, flags.keep_going, flags.no_inherit);
+    if let (None, Some(PrintFormat::Lines)) = (format, print) {
+        format = default_format;
+    }
+    let unused_prefix = match prefix_from {
+        Some(n) if n > 0 && n == sources.len() => Some(prefix.clone()),
+        _ => None,
+    };
+    if sources.is_empty() {
+        sources.push((file_source(".env.toml", None), prefix));
+    }
+    if no_inherit && sources.iter().any(|(s, _)| matches!(s, Source::EnvVar(_))) {
+        return ArgParseResult::Err("--from-env can't be used with --no-inherit.");
+    }
+    if cfg!(not(feature = "url")) && sources.iter().any(|(s, _)| matches!(s, Source::Url(_))) {
+        return ArgParseResult::Err("--url needs envf to be built with the url feature.");
+    }
+    if cfg!(not(unix)) && user.is_some() {
+        return ArgParseResult::Err("--user is only supported on unix.");
+    }
+    if silent && strict {
+        return ArgParseResult::Err("-s and --strict can't be used together.");
+    }
+    if validate && parse_only {
+        return ArgParseResult::Err("--validate and --parse-only can't be used together.");
+    }
+    if keep_going && strict {
+        return ArgParseResult::Err("--keep-going and --strict can't be used together.");
+    }
+    if sources.iter().filter(|(source, _)| reads_stdin(source)).count() > 1 {
+        return ArgParseResult::Err(STDIN_ERROR);
+    }
+    let print = match (print, format) {
+        (Some(PrintFormat::Export(_)), Some(_)) => {
+            return ArgParseResult::Err("--export and --format can't be used together.")
+        }
+        (None, Some(_)) => return ArgParseResult::Err("--format only applies to --print."),
+        (Some(_), Some(f)) => Some(f),
+        (p, None) => p,
+    };
+    let print = match (print, quote_style) {
+        (Some(PrintFormat::Export(_)), Some(style)) => Some(PrintFormat::Export(style)),
+        (_, Some(_)) => return ArgParseResult::Err("--quote-style only applies to --export."),
+        (p, None) => p,
+    };
+    if diff.is_some() && print.is_some() {
+        return ArgParseResult::Err("--diff can't be used with --print and its variants.");
+    }
+    if output.is_some() && print.is_none() {
+        return ArgParseResult::Err("-o only applies to --print and its variants.");
+    }
+    if !silent {
+        load.warn = warning;
+    }
+    let cmd: Vec<String> = args.collect();
+    let commands: Vec<Vec<String>> = if cmd.is_empty() {
+        vec![]
+    } else if chain {
+        cmd.split(|arg| arg == COMMAND_SEPARATOR).map(<[String]>::to_vec).collect()
+    } else {
+        vec![cmd]
+    };
+    if commands.iter().any(Vec::is_empty) {
+        return ArgParseResult::Err("--- must be between two commands.");
+    }
+    ArgParseResult::Config(Box::new(Config {
+        sources,
+        environment,
+        layer,
+        silent,
+        strict,
+        fail_on_skip,
+        on_missing_file,
+        keep_going,
+        require_nonempty,
+        dump_config,
+        validate,
+        parse_only,
+        dry_run,
+        echo_command,
+        allow_empty_command,
+        explain,
+        rc_file,
+        project,
+        summary,
+        allow_unset,
+        print,
+        output,
+        diff,
+        trace,
+        log_json,
+        color,
+        chdir,
+        expand_tilde,
+        defaults_only,
+        no_override,
+        max_value_len,
+        user,
+        spawn,
+        timeout,
+        retry,
+        retry_delay,
+        command: commands,
+        overrides,
+        defaults,
+        renames,
+        map_files,
+        unset,
+        append_path,
+        only,
+        exclude,
+        require,
+        clear,
+        no_inherit,
+        unused_prefix,
+        key_case,
+        dots_to_underscores,
+        load,
+    }))
+}
+
+/// Inserts `.name` before the extension of `path`, if any, so that the
+/// layer is read in the same format. A trailing `.gz` is kept last.
+fn layer_path(path: &str, name: &str) -> String {
+    if let Some(uncompressed) = path.strip_suffix(".gz") {
+        return format!("{}.gz", layer_path(uncompressed, name));
+    }
+    let p = Path::new(path);
+    let layered = match (p.file_stem(), p.extension()) {
+        (Some(stem), Some(ext)) => format!(
+            "{}.{}.{}",
+            stem.to_string_lossy(),
+            name,
+            ext.to_string_lossy()
+        ),
+        _ => format!("{}.{}", p.file_name().unwrap_or_default().to_string_lossy(), name),
+    };
+    p.with_file_name(layered).to_string_lossy().into_owned()
+}
+
+fn file_list_source(path: &str, format: Option<FileFormat>, prefix: &str) -> (Source, String) {
+    let path = path.to_string();
+    (Source::FileList { path, format }, prefix.to_string())
+}
+
+/// What separates the commands to run in turn with --chain.
+const COMMAND_SEPARATOR: &str = "---";
+
+/// The command run by --allow-empty-command when none is given: the user's
+/// shell, according to SHELL if `inherit`, or else /bin/sh if it exists.
+fn default_shell(inherit: bool) -> Option<String> {
+    match env::var("SHELL") {
+        Ok(shell) if inherit && !shell.is_empty() => Some(shell),
+        _ if Path::new("/bin/sh").is_file() => Some("/bin/sh".to_string()),
+        _ => None,
+    }
+}
+
+/// Reads the renames of the --map-file at `path`, sorted by old name, exiting
+/// with an error if it can't be loaded.
+fn read_map_file(path: &str, config: &Config) -> Vec<(String, String)> {
+    match read_env_file(&expand_file_path(path, config), &LoadOptions::default()) {
+        Err(err) => error_without_usage(&format!(
+            "Couldn't read map file {}: {}",
+            path,
+            config.describe(&err)
+        )),
+        Ok(m) => {
+            let mut renames: Vec<(String, String)> = m
+                .into_iter()
+                .filter_map(|(k, v)| v.into_string().map(|v| (k, v)))
+                .collect();
+            renames.sort();
+            for (old, new) in &renames {
+                if let Some(problem) = unusable_variable(new, "") {
+                    error_without_usage(&format!(
+                        "Map file {} can't rename {}: {}",
+                        path, old, problem
+                    ));
+                }
+            }
+            renames
+        }
+    }
+}
+
+/// Builds the source for the FILE of `-f FILE`, which is required if it
+/// starts with `!`.
+fn file_source(path: &str, format: Option<FileFormat>) -> Source {
+    match path.strip_prefix('!') {
+        Some(path) => Source::File {
+            path: path.to_string(),
+            required: true,
+            format,
+        },
+        None => Source::File {
+            path: path.to_string(),
+            required: false,
+            format,
+        },
+    }
+}
+
+fn parse_assignment(s: &str) -> Option<(String, String)> {
+    s.find('=')
+        .map(|i| (String::from(&s[..i]), String::from(&s[i + 1..])))
+}
+
+const RENAME_ERROR: &str = "--rename expects OLD=NEW, where NEW can be a variable name";
+
+fn parse_rename(s: &str) -> Option<(String, String)> {
+    parse_assignment(s).filter(|(_, new)| unusable_variable(new, "").is_none())
+}
+
+fn parse_print_format(s: &str) -> Option<PrintFormat> {
+    match s {
+        "plain" => Some(PrintFormat::Lines),
+        "json" => Some(PrintFormat::Json),
+        _ => None,
+    }
+}
+
+const FILE_FORMAT_ERROR: &str = "--env-file-format expects auto, toml, json, yaml or dotenv";
+
+/// Parses the FORMAT of --env-file-format; auto is `Some(None)`.
+fn parse_file_format(s: &str) -> Option<Option<FileFormat>> {
+    match s {
+        "auto" => Some(None),
+        "toml" => Some(Some(FileFormat::Document(Format::Toml))),
+        "json" => Some(Some(FileFormat::Document(Format::Json))),
+        "yaml" => Some(Some(FileFormat::Document(Format::Yaml))),
+        "dotenv" => Some(Some(FileFormat::Dotenv)),
+        _ => None,
+    }
+}
+
+const MAX_VALUE_LEN_ERROR: &str = "--max-value-len expects a number of bytes";
+
+const RETRY_ERROR: &str = "--retry expects a number of attempts";
+
+const RETRY_DELAY_ERROR: &str = "--retry-delay expects a number of milliseconds";
+
+const TIMEOUT_ERROR: &str = "--timeout expects a number of seconds";
+
+/// Parses the SECONDS of --timeout; 0 is `Some(None)`, for no timeout.
+fn parse_timeout(s: &str) -> Option<Option<Duration>> {
+    match s.parse::<f64>().ok().map(Duration::try_from_secs_f64) {
+        Some(Ok(timeout)) if timeout.is_zero() => Some(None),
+        Some(Ok(timeout)) => Some(Some(timeout)),
+        _ => None,
+    }
+}
+
+const KEY_CASE_ERROR: &str = "--key-case expects none, upper, lower or snake";
+
+fn parse_key_case(s: &str) -> Option<KeyCase> {
+    match s {
+        "none" => Some(KeyCase::None),
+        "upper" => Some(KeyCase::Upper),
+        "lower" => Some(KeyCase::Lower),
+        "snake" => Some(KeyCase::Snake),
+        _ => None,
+    }
+}
+
+const QUOTE_STYLE_ERROR: &str = "--quote-style expects single, double or none";
+
+fn parse_quote_style(s: &str) -> Option<QuoteStyle> {
+    match s {
+        "single" => Some(QuoteStyle::Single),
+        "double" => Some(QuoteStyle::Double),
+        "none" => Some(QuoteStyle::None),
+        _ => None,
+    }
+}
+
+const MISSING_FILE_POLICY_ERROR: &str = "--on-missing-file expects error, warn or ignore";
+
+fn parse_missing_file_policy(s: &str) -> Option<MissingFilePolicy> {
+    match s {
+        "error" => Some(MissingFilePolicy::Error),
+        "warn" => Some(MissingFilePolicy::Warn),
+        "ignore" => Some(MissingFilePolicy::Ignore),
+        _ => None,
+    }
+}
+
+const COLOR_MODE_ERROR: &str = "--color expects auto, always or never";
+
+fn parse_color_mode(s: &str) -> Option<ColorMode> {
+    match s {
+        "auto" => Some(ColorMode::Auto),
+        "always" => Some(ColorMode::Always),
+        "never" => Some(ColorMode::Never),
+        _ => None,
+    }
+}
+
+const BOOL_STYLE_ERROR: &str = "--bool-style expects true-false, one-zero or yes-no";
+
+fn parse_bool_style(s: &str) -> Option<BoolStyle> {
+    match s {
+        "true-false" => Some(BoolStyle::TrueFalse),
+        "one-zero" => Some(BoolStyle::OneZero),
+        "yes-no" => Some(BoolStyle::YesNo),
+        _ => None,
+    }
+}
+
+const DATETIME_FORMAT_ERROR: &str = "--datetime-format expects rfc3339 or epoch";
+
+fn parse_datetime_format(s: &str) -> Option<DatetimeFormat> {
+    match s {
+        "rfc3339" => Some(DatetimeFormat::Rfc3339),
+        "epoch" => Some(DatetimeFormat::Epoch),
+        _ => None,
+    }
+}
+
+const INT_RADIX_ERROR: &str = "--int-radix expects dec, hex, oct or bin";
+
+fn parse_int_radix(s: &str) -> Option<IntRadix> {
+    match s {
+        "dec" => Some(IntRadix::Dec),
+        "hex" => Some(IntRadix::Hex),
+        "oct" => Some(IntRadix::Oct),
+        "bin" => Some(IntRadix::Bin),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+
+    fn config(args: &[&str]) -> Config {
+        match parse_args(args.iter().map(|arg| arg.to_string())) {
+            ArgParseResult::Config(config) => *config,
+            _ => panic!("invalid arguments: {:?}", args),
+        }
+    }
+
+    #[test]
+    fn build_command_passes_the_variables() {
+        let config = config(&["--unset", "HOME", "--chdir", "/tmp", "printenv", "A"]);
+        let mut map = EnvMap::new();
+        map.insert(String::from("A"), String::from("1"));
+        let command = build_command(&config, &config.command[0], &map, None);
+        assert_eq!(command.get_program(), "printenv");
+        assert_eq!(command.get_args().collect::<Vec<_>>(), ["A"]);
+        assert_eq!(command.get_current_dir(), Some(Path::new("/tmp")));
+        let envs: Vec<(&OsStr, Option<&OsStr>)> = command.get_envs().collect();
+        assert!(envs.contains(&(OsStr::new("A"), Some(OsStr::new("1")))));
+        assert!(envs.contains(&(OsStr::new("HOME"), None)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn user_ids_looks_numeric_ids_up() {
+        let root = user_ids("root").unwrap();
+        assert_eq!(user_ids("0").unwrap(), root);
+        assert_eq!(root.gid, 0);
+        assert!(root.groups.contains(&0));
+        // No user is likely to have that ID.
+        assert!(user_ids("4000000007").is_err());
+        let ids = user_ids("4000000007:0").unwrap();
+        assert_eq!(ids, UserIds { uid: 4000000007, gid: 0, groups: vec![0] });
+    }
+
+    #[test]
+    fn expand_sources_leaves_urls_alone() {
+        let config = config(&["printenv"]);
+        let url = "https://example.com/~envf/vars?$filter=a";
+        let sources = vec![(Source::Url(String::from(url)), String::new())];
+        match &expand_sources(sources, &config)[..] {
+            [(Source::Url(expanded), _)] => assert_eq!(expanded, url),
+            _ => panic!("expected a single URL"),
+        }
+    }
+
+    #[test]
+    fn layer_path_keeps_the_extensions() {
+        assert_eq!(layer_path("conf/app.toml", "local"), "conf/app.local.toml");
+        assert_eq!(layer_path("app.json.gz", "local"), "app.local.json.gz");
+        assert_eq!(layer_path("app.gz", "production"), "app.production.gz");
+        assert_eq!(layer_path("app", "local"), "app.local");
+    }
+
+    #[test]
+    fn parse_args_stops_at_double_dash() {
+        let config = config(&["-f", "a.toml", "--", "-f", "b.toml"]);
+        assert_eq!(config.sources.len(), 1);
+        assert_eq!(config.command, [["-f", "b.toml"]]);
+    }
+
+    #[test]
+    fn parse_args_only_splits_commands_with_chain() {
+        let single = config(&["--", "printf", "%s\\n", "x", "---"]);
+        assert_eq!(single.command, [["printf", "%s\\n", "x", "---"]]);
+        let chained = config(&["--chain", "--", "setup", "a", "---", "main", "b"]);
+        assert_eq!(chained.command, [["setup", "a"], ["main", "b"]]);
     }
 }
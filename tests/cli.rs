@@ -0,0 +1,205 @@
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{self, Command, Output, Stdio};
+use std::thread;
+use std::time::Duration;
+
+fn fixture(name: &str) -> String {
+    format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+/// An empty directory for the files of the test `name`.
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("envf-{}-{}", process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Runs envf with `args`, ignoring the ~/.envfrc of whoever runs the tests.
+fn envf(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_envf"))
+        .arg("--config=")
+        .args(args)
+        .output()
+        .expect("envf to run")
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[test]
+fn fail_on_skip_still_runs_the_command() {
+    let missing = fixture("missing.toml");
+    let args = ["--fail-on-skip", "-f", &missing, "-f", &fixture("a.toml"), "printenv", "A"];
+    let output = envf(&args);
+    assert_eq!(stdout(&output), "1\n");
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn fail_on_skip_fails_after_printing() {
+    let missing = fixture("missing.toml");
+    let output = envf(&["--fail-on-skip", "-f", &missing, "-f", &fixture("a.toml"), "--print"]);
+    assert_eq!(stdout(&output), "A=1\n");
+    assert_eq!(output.status.code(), Some(1));
+    let output = envf(&["--fail-on-skip", "-f", &fixture("a.toml"), "--print"]);
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn retry_waits_for_a_file_to_appear() {
+    let dir = scratch_dir("retry");
+    let path = dir.join("late.toml");
+    let writer = {
+        let (written, path) = (dir.join("late.tmp"), path.clone());
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            // Renamed into place so that envf never reads half of it.
+            fs::write(&written, "A = \"1\"\n").unwrap();
+            fs::rename(written, path).unwrap();
+        })
+    };
+    let path = path.to_string_lossy().into_owned();
+    let output = envf(&["--strict", "--retry=50", "--retry-delay=100", "-f", &path, "--print"]);
+    writer.join().unwrap();
+    assert_eq!(stdout(&output), "A=1\n");
+    assert_eq!(output.status.code(), Some(0));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn env_file_is_loaded_over_the_base_file() {
+    let output = envf(&["--env", "production", "-f", &fixture("app.toml"), "--print"]);
+    assert_eq!(stdout(&output), "HOST=prod.example.com\nPORT=8080\n");
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn missing_env_file_is_ignored_unless_strict() {
+    let app = fixture("app.toml");
+    let output = envf(&["--env", "staging", "-f", &app, "--print"]);
+    assert_eq!(stdout(&output), "HOST=localhost\nPORT=8080\n");
+    assert_eq!(output.status.code(), Some(0));
+    let output = envf(&["--strict", "--env", "staging", "-f", &app, "--print"]);
+    assert_eq!(stdout(&output), "");
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn templates_follow_renamed_keys() {
+    let path = fixture("compute_lower.toml");
+    let output = envf(&["--upper", "-f", &path, "--print"]);
+    assert_eq!(stdout(&output), "HOST=x\nURL=http://x\n");
+    assert_eq!(output.status.code(), Some(0));
+    let output = envf(&["--prefix", "APP_", "-f", &path, "--print"]);
+    assert_eq!(stdout(&output), "APP_host=x\nAPP_url=http://x\n");
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn append_path_adds_no_empty_entry() {
+    let path = fixture("empty_path.toml");
+    let output = Command::new(env!("CARGO_BIN_EXE_envf"))
+        .args(["--config=", "--append-path", "PATH", "-f", &path, "--print"])
+        .env("PATH", "/usr/bin:/bin")
+        .output()
+        .expect("envf to run");
+    assert_eq!(stdout(&output), "PATH=/usr/bin:/bin\n");
+}
+
+#[test]
+fn files_ignored_on_purpose_are_not_skipped() {
+    let missing = fixture("missing.toml");
+    let args = ["--on-missing-file", "ignore", "--fail-on-skip", "--summary", "-f", &missing];
+    let output = envf(&[&args[..], &["-f", &fixture("a.toml"), "--print"]].concat());
+    assert_eq!(stdout(&output), "A=1\n");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("(0 ignored)"));
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn file_lists_can_name_the_standard_input() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_envf"))
+        .args(["--config=", "--file-list", &fixture("stdin.list"), "--print"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("envf to run");
+    child.stdin.take().unwrap().write_all(b"B = \"2\"\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(stdout(&output), "A=1\nB=2\n");
+    let output = envf(&["--file-list", &fixture("stdin.list"), "-f", "-", "--print"]);
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn rename_rejects_unusable_names() {
+    for rename in ["A=B=C", "A="] {
+        let output = envf(&["-f", &fixture("a.toml"), "--rename", rename, "--print"]);
+        assert_eq!(stdout(&output), "");
+        assert_eq!(output.status.code(), Some(1));
+    }
+    let output = envf(&["-f", &fixture("a.toml"), "--rename=A=B", "--print"]);
+    assert_eq!(stdout(&output), "B=1\n");
+}
+
+#[test]
+fn map_file_rejects_unusable_names() {
+    let map_file = fixture("bad_map.toml");
+    let output = envf(&["-f", &fixture("a.toml"), "--map-file", &map_file, "--print"]);
+    assert_eq!(stdout(&output), "");
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn int_u64_writes_digit_strings_as_integers() {
+    let dir = scratch_dir("int-u64");
+    let path = dir.join("big.toml");
+    let body = "BIG = \"18446744073709551615\"\nZIP = \"007\"\nHUGE = \"99999999999999999999\"\n";
+    fs::write(&path, body).unwrap();
+    let path = path.to_string_lossy();
+    let output = envf(&["--int-u64", "--int-radix=hex", "--radix-prefix", "-f", &path, "--print"]);
+    assert_eq!(stdout(&output), "BIG=0xffffffffffffffff\nHUGE=99999999999999999999\nZIP=007\n");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("HUGE (99999999999999999999) doesn't fit in 64 bits"), "{}", stderr);
+    let output = envf(&["-f", &path, "--print"]);
+    assert!(stdout(&output).starts_with("BIG=18446744073709551615\n"));
+}
+
+#[test]
+fn int_radix_reports_only_the_integers_shells_misread() {
+    let dir = scratch_dir("int-radix");
+    let path = dir.join("ints.toml");
+    fs::write(&path, "A = 255\nB = 16\n").unwrap();
+    let output = envf(&["--int-radix=hex", "-f", &path.to_string_lossy(), "--print"]);
+    assert_eq!(stdout(&output), "A=ff\nB=10\n");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("for A"), "{}", stderr);
+    assert!(stderr.contains("B (10) would be read by shells as 10 rather than 16"), "{}", stderr);
+}
+
+#[test]
+fn clear_hides_the_current_environment_from_envf_too() {
+    let dir = scratch_dir("clear");
+    let path = dir.join("bar.toml");
+    fs::write(&path, "BAR = \"${FOO}\"\n").unwrap();
+    let path = path.to_string_lossy();
+    let run = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_envf"))
+            .arg("--config=")
+            .args(args)
+            .env("FOO", "outer")
+            .output()
+            .expect("envf to run")
+    };
+    let output = run(&["--default", "FOO=inner", "-f", &path, "--print"]);
+    assert_eq!(stdout(&output), "BAR=outer\n");
+    let output = run(&["--clear", "--default", "FOO=inner", "-f", &path, "--print"]);
+    assert_eq!(stdout(&output), "BAR=inner\nFOO=inner\n");
+    assert_eq!(run(&["--require", "FOO", "--print"]).status.code(), Some(0));
+    assert_ne!(run(&["--clear", "--require", "FOO", "--print"]).status.code(), Some(0));
+}
@@ -0,0 +1,149 @@
+extern crate envf;
+
+use std::time::{Duration, Instant};
+
+use envf::{
+    load_files, parse_env, read_env_file, Change, EnvfError, LoadOptions, Merge, Value,
+};
+
+fn fixture(name: &str) -> String {
+    format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+#[test]
+fn load_files_fills_templates_from_the_merged_variables() {
+    let paths = [fixture("compute.toml"), fixture("host.toml")];
+    let map = load_files(&paths, &LoadOptions::default()).unwrap();
+    assert_eq!(map["HOST"], "override");
+    assert_eq!(map["URL"], "http://override");
+}
+
+#[test]
+fn only_the_unset_table_unsets() {
+    let body = "A = { unset = true }\nB = \"\\u0000unset\"\n";
+    let map = parse_env("unset.toml", body, &LoadOptions::default()).unwrap();
+    assert_eq!(map["A"], Value::Unset);
+    assert_eq!(map["B"], Value::String(String::from("\0unset")));
+}
+
+#[test]
+fn load_files_appends_to_keys_ending_in_plus() {
+    let paths = [fixture("path.toml"), fixture("path_append.toml")];
+    let map = load_files(&paths, &LoadOptions::default()).unwrap();
+    assert_eq!(map.len(), 1);
+    assert_eq!(map["PATH"], "/usr/bin:/opt/bin");
+}
+
+/// A document with `n` tables of 10 keys each. With `n = 10_000`, copying the
+/// variables read so far for each key, as envf once did, takes minutes.
+fn big_table(n: usize) -> String {
+    let mut body = String::new();
+    for i in 0..n {
+        body.push_str(&format!("[T{}]\n", i));
+        for j in 0..10 {
+            body.push_str(&format!("K{} = \"value {} {}\"\n", j, i, j));
+        }
+    }
+    body
+}
+
+#[test]
+fn parse_only_reads_no_variables() {
+    let options = LoadOptions {
+        parse_only: true,
+        ..LoadOptions::default()
+    };
+    let body = format!("{}NESTED = [[1], [2]]\n", big_table(10_000));
+    let start = Instant::now();
+    let map = parse_env("big.toml", &body, &options).unwrap();
+    assert!(map.is_empty());
+    assert!(start.elapsed() < Duration::from_secs(10), "took {:?}", start.elapsed());
+    assert!(parse_env("big.toml", "A = [", &options).is_err());
+}
+
+#[test]
+fn yaml_scalars_are_stringified() {
+    let map = read_env_file(&fixture("scalars.yaml"), &LoadOptions::default()).unwrap();
+    let string = |s: &str| Value::String(String::from(s));
+    assert_eq!(map.len(), 7);
+    assert_eq!(map["NAME"], string("envf"));
+    assert_eq!(map["QUOTED"], string("42"));
+    assert_eq!(map["PORT"], string("8080"));
+    assert_eq!(map["NEGATIVE"], string("-3"));
+    assert_eq!(map["RATIO"], string("0.5"));
+    assert_eq!(map["DEBUG"], string("true"));
+    assert_eq!(map["VERBOSE"], string("false"));
+}
+
+#[test]
+fn yaml_nulls_name_their_key() {
+    let options = LoadOptions::default();
+    match parse_env("ports.yaml", "PORTS: [80, ~]\n", &options) {
+        Err(EnvfError::Null { key }) => assert_eq!(key, "PORTS"),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    match parse_env("null.yaml", "~\n", &options) {
+        Err(EnvfError::NotATable(_)) => {}
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn arrays_of_tables_are_indexed() {
+    let body = "[[servers]]\nhost = \"a\"\n\n[[servers]]\nhost = \"b\"\nport = 22\n";
+    let map = parse_env("servers.toml", body, &LoadOptions::default()).unwrap();
+    assert_eq!(map.len(), 3);
+    assert_eq!(map["servers_0_host"], Value::String(String::from("a")));
+    assert_eq!(map["servers_1_host"], Value::String(String::from("b")));
+    assert_eq!(map["servers_1_port"], Value::String(String::from("22")));
+    let options = LoadOptions {
+        separator: String::from("__"),
+        ..LoadOptions::default()
+    };
+    let map = parse_env("servers.toml", body, &options).unwrap();
+    assert!(map.contains_key("servers__1__port"));
+}
+
+#[test]
+fn inline_tables_flatten_like_sections() {
+    let options = LoadOptions::default();
+    let inline = parse_env("db.toml", "db = { host = \"x\", port = 5 }\n", &options).unwrap();
+    let section = parse_env("db.toml", "[db]\nhost = \"x\"\nport = 5\n", &options).unwrap();
+    let dotted = parse_env("db.toml", "db.host = \"x\"\ndb.port = 5\n", &options).unwrap();
+    assert_eq!(inline.len(), 2);
+    assert_eq!(inline, section);
+    assert_eq!(inline, dotted);
+}
+
+#[test]
+fn large_tables_load_quickly() {
+    let body = big_table(10_000);
+    let start = Instant::now();
+    let map = parse_env("big.toml", &body, &LoadOptions::default()).unwrap();
+    assert!(start.elapsed() < Duration::from_secs(10), "took {:?}", start.elapsed());
+    assert_eq!(map.len(), 100_000);
+    assert_eq!(map["T9999_K9"], Value::String(String::from("value 9999 9")));
+    let body = format!("{}NESTED = [[1], [2]]\n", body);
+    match parse_env("big.toml", &body, &LoadOptions::default()) {
+        Err(EnvfError::NonScalar { key, .. }) => assert_eq!(key, "T9999_NESTED"),
+        result => panic!("expected a non-scalar error, got {:?}", result),
+    }
+}
+
+#[test]
+fn renamed_keys_still_append() {
+    let options = LoadOptions::default();
+    let read = |name| read_env_file(&fixture(name), &options).unwrap();
+    let mut merge = Merge::new(&options);
+    merge.add_renamed_file("path.toml", read("path.toml"), |k| format!("APP_{}", k));
+    let changes = merge.add_renamed_file("path_append.toml", read("path_append.toml"), |k| {
+        format!("APP_{}", k)
+    });
+    let extended = Change::Set {
+        previous: Some(String::from("path.toml")),
+        extended: true,
+    };
+    assert_eq!(changes, [(String::from("APP_PATH"), extended)]);
+    assert_eq!(merge.map.len(), 1);
+    assert_eq!(merge.map["APP_PATH"], "/usr/bin:/opt/bin");
+}